@@ -60,6 +60,13 @@ impl<T: Send + Sync + ?Sized> Registry<T> {
 			.filter_map(|pair| pair.1.upgrade())
 			.collect()
 	}
+	/// Number of entries that are still alive, without collecting them.
+	pub fn count(&self) -> usize {
+		self.lock()
+			.values()
+			.filter(|v| v.strong_count() > 0)
+			.count()
+	}
 	pub fn set(&self, other: &Registry<T>) {
 		self.lock().clone_from(&other.lock());
 	}
@@ -80,6 +87,10 @@ impl<T: Send + Sync + ?Sized> Registry<T> {
 			(f)(&v)
 		})
 	}
+	/// Drop the entries whose value has already been freed.
+	pub fn prune(&self) {
+		self.lock().retain(|_, v| v.strong_count() > 0)
+	}
 	pub fn remove(&self, t: &T) {
 		self.lock()
 			.remove(&(ptr::addr_of!(*t) as *const () as usize));
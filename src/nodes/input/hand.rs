@@ -72,7 +72,7 @@ impl InputDataTrait for Hand {
 	}
 	fn transform(&mut self, method: &InputMethod, handler: &InputHandler) {
 		let local_to_handler_matrix =
-			Spatial::space_to_space_matrix(Some(&method.spatial), Some(&handler.spatial));
+			Spatial::space_to_space_matrix(Some(method.spatial_ref()), Some(&handler.spatial));
 		let mut joints: Vec<&mut Joint> = Vec::new();
 
 		joints.extend([&mut self.palm, &mut self.wrist]);
@@ -1,12 +1,23 @@
-use super::{InputHandlerAspect, INPUT_HANDLER_REGISTRY, INPUT_METHOD_REGISTRY};
+use super::{
+	InputHandlerAspect, INPUT_HANDLER_REGISTRY, INPUT_METHOD_REGISTRY, INPUT_REGISTRATION_LOCK,
+};
 use crate::nodes::{alias::AliasList, fields::Field, spatial::Spatial, Aspect, Node};
 use color_eyre::eyre::Result;
-use std::sync::Arc;
+use once_cell::sync::Lazy;
+use portable_atomic::{AtomicU64, Ordering};
+use std::{
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+/// Reference point for `last_send_ns`, since an `Instant` can't be stored atomically.
+static INPUT_EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
 
 pub struct InputHandler {
 	pub spatial: Arc<Spatial>,
 	pub field: Arc<Field>,
 	pub(super) method_aliases: AliasList,
+	last_send_ns: AtomicU64,
 }
 impl InputHandler {
 	pub fn add_to(node: &Arc<Node>, field: &Arc<Field>) -> Result<()> {
@@ -14,14 +25,26 @@ impl InputHandler {
 			spatial: node.get_aspect::<Spatial>().unwrap().clone(),
 			field: field.clone(),
 			method_aliases: AliasList::default(),
+			last_send_ns: AtomicU64::new(0),
 		};
+		let registration_lock = INPUT_REGISTRATION_LOCK.lock();
 		for method in INPUT_METHOD_REGISTRY.get_valid_contents() {
 			method.handle_new_handler(&handler);
 		}
 		let handler = INPUT_HANDLER_REGISTRY.add(handler);
+		drop(registration_lock);
 		node.add_aspect_raw(handler);
 		Ok(())
 	}
+
+	/// When input was last sent to this handler's client.
+	pub fn last_send_time(&self) -> Instant {
+		*INPUT_EPOCH + Duration::from_nanos(self.last_send_ns.load(Ordering::Relaxed))
+	}
+	pub(super) fn mark_sent(&self) {
+		let ns = INPUT_EPOCH.elapsed().as_nanos() as u64;
+		self.last_send_ns.store(ns, Ordering::Relaxed);
+	}
 }
 impl Aspect for InputHandler {
 	const NAME: &'static str = "InputHandler";
@@ -1,7 +1,7 @@
 use super::{
 	input_method_client, InputData, InputDataTrait, InputDataType, InputHandler, InputMethodAspect,
 	InputMethodRefAspect, INPUT_HANDLER_REGISTRY, INPUT_METHOD_REF_ASPECT_ALIAS_INFO,
-	INPUT_METHOD_REGISTRY,
+	INPUT_METHOD_REGISTRY, INPUT_REGISTRATION_LOCK,
 };
 use crate::{
 	core::{client::Client, registry::Registry},
@@ -47,14 +47,40 @@ impl InputMethod {
 		};
 		<InputMethod as InputMethodRefAspect>::add_node_members(node);
 		<InputMethod as InputMethodAspect>::add_node_members(node);
+		let registration_lock = INPUT_REGISTRATION_LOCK.lock();
 		for handler in INPUT_HANDLER_REGISTRY.get_valid_contents() {
 			method.handle_new_handler(&handler);
 		}
 		let method = INPUT_METHOD_REGISTRY.add(method);
+		drop(registration_lock);
 		node.add_aspect_raw(method.clone());
 		Ok(method)
 	}
 
+	/// Short description for logs, e.g. `hand:42[3 handlers, captured_by=1]`.
+	pub fn debug_name(&self) -> String {
+		let data_type_name = match &*self.data.lock() {
+			InputDataType::Pointer(_) => "pointer",
+			InputDataType::Hand(_) => "hand",
+			InputDataType::Tip(_) => "tip",
+		};
+		let id = self
+			.spatial
+			.node()
+			.map(|node| node.get_id().to_string())
+			.unwrap_or_else(|| "?".to_string());
+		format!(
+			"{data_type_name}:{id}[{} handlers, captured_by={}]",
+			self.handler_order.lock().len(),
+			self.captures.count()
+		)
+	}
+
+	/// The space input is emitted from, which distances to handler fields are measured in.
+	pub fn spatial_ref(&self) -> &Arc<Spatial> {
+		&self.spatial
+	}
+
 	pub fn distance(&self, to: &Field) -> f32 {
 		self.data.lock().distance(&self.spatial, to)
 	}
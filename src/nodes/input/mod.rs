@@ -17,11 +17,16 @@ use crate::nodes::spatial::SPATIAL_REF_ASPECT_ALIAS_INFO;
 use crate::{core::client::Client, nodes::Node};
 use crate::{core::registry::Registry, nodes::spatial::Transform};
 use color_eyre::eyre::Result;
+use parking_lot::{const_mutex, Mutex};
 use stardust_xr::values::Datamap;
 use std::sync::Arc;
+use tracing::debug_span;
 
 static INPUT_METHOD_REGISTRY: Registry<InputMethod> = Registry::new();
 pub static INPUT_HANDLER_REGISTRY: Registry<InputHandler> = Registry::new();
+/// Held while a method or handler links itself to everything on the other side and registers,
+/// so a method and handler created at the same time can't both miss each other.
+static INPUT_REGISTRATION_LOCK: Mutex<()> = const_mutex(());
 
 stardust_xr_server_codegen::codegen_input_protocol!();
 
@@ -89,6 +94,48 @@ impl InterfaceAspect for InputInterface {
 	}
 }
 
+#[derive(Debug, serde::Serialize)]
+pub struct InputMethodDebugInfo {
+	pub id: Option<u64>,
+	pub enabled: bool,
+	pub handler_count: usize,
+	pub capture_count: usize,
+}
+#[derive(Debug, serde::Serialize)]
+pub struct InputHandlerDebugInfo {
+	pub id: Option<u64>,
+	pub enabled: bool,
+	pub secs_since_last_send: f64,
+}
+pub fn debug_info() -> (Vec<InputMethodDebugInfo>, Vec<InputHandlerDebugInfo>) {
+	let methods = INPUT_METHOD_REGISTRY
+		.get_valid_contents()
+		.into_iter()
+		.map(|method| {
+			let node = method.spatial.node();
+			InputMethodDebugInfo {
+				id: node.as_ref().map(|n| n.get_id()),
+				enabled: node.as_ref().is_some_and(|n| n.enabled()),
+				handler_count: method.handler_order.lock().len(),
+				capture_count: method.captures.count(),
+			}
+		})
+		.collect();
+	let handlers = INPUT_HANDLER_REGISTRY
+		.get_valid_contents()
+		.into_iter()
+		.map(|handler| {
+			let node = handler.spatial.node();
+			InputHandlerDebugInfo {
+				id: node.as_ref().map(|n| n.get_id()),
+				enabled: node.as_ref().is_some_and(|n| n.enabled()),
+				secs_since_last_send: handler.last_send_time().elapsed().as_secs_f64(),
+			}
+		})
+		.collect();
+	(methods, handlers)
+}
+
 #[tracing::instrument(level = "debug")]
 pub fn process_input() {
 	// Iterate over all valid input methods
@@ -141,9 +188,13 @@ pub fn process_input() {
 				a.set_enabled(true);
 			})
 			// serialize the data
-			.map(|(a, m)| (a.clone(), m.serialize(a.get_id(), &handler)))
+			.map(|(a, m)| {
+				debug_span!("Serialize input", method = m.debug_name())
+					.in_scope(|| (a.clone(), m.serialize(a.get_id(), &handler)))
+			})
 			.unzip::<_, _, Vec<_>, Vec<_>>();
 
+		handler.mark_sent();
 		let _ = input_handler_client::input(&handler_node, &methods, &datas);
 	}
 	for method in methods {
@@ -36,10 +36,13 @@ impl InputDataTrait for Pointer {
 	fn transform(&mut self, method: &InputMethod, handler: &InputHandler) {
 		let local_to_handler_matrix =
 			Mat4::from_rotation_translation(self.orientation.into(), self.origin.into())
-				* Spatial::space_to_space_matrix(Some(&method.spatial), Some(&handler.spatial));
+				* Spatial::space_to_space_matrix(
+					Some(method.spatial_ref()),
+					Some(&handler.spatial),
+				);
 		let (_, orientation, origin) = local_to_handler_matrix.to_scale_rotation_translation();
 
-		let ray_march = self.ray_march(&method.spatial, &handler.field);
+		let ray_march = self.ray_march(method.spatial_ref(), &handler.field);
 		let direction = local_to_handler_matrix
 			.transform_vector3(vec3(0.0, 0.0, -1.0))
 			.normalize();
@@ -20,7 +20,7 @@ impl InputDataTrait for Tip {
 	}
 	fn transform(&mut self, method: &InputMethod, handler: &InputHandler) {
 		let local_to_handler_matrix =
-			Spatial::space_to_space_matrix(Some(&method.spatial), Some(&handler.spatial))
+			Spatial::space_to_space_matrix(Some(method.spatial_ref()), Some(&handler.spatial))
 				* Mat4::from_rotation_translation(self.orientation.into(), self.origin.into());
 		let (_, orientation, origin) = local_to_handler_matrix.to_scale_rotation_translation();
 		self.origin = origin.into();
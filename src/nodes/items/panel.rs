@@ -30,6 +30,42 @@ impl Default for Geometry {
 		}
 	}
 }
+impl Geometry {
+	/// Rectangle spanning two corners, `None` if `max` is left of or above `min`.
+	pub fn from_min_max(min: Vector2<i32>, max: Vector2<i32>) -> Option<Geometry> {
+		if max.x < min.x || max.y < min.y {
+			return None;
+		}
+		Some(Geometry {
+			origin: min,
+			size: [(max.x - min.x) as u32, (max.y - min.y) as u32].into(),
+		})
+	}
+	fn max(&self) -> Vector2<i32> {
+		[
+			self.origin.x + self.size.x as i32,
+			self.origin.y + self.size.y as i32,
+		]
+		.into()
+	}
+	/// The overlapping area, `None` if it would be empty.
+	pub fn intersection(&self, other: &Geometry) -> Option<Geometry> {
+		let (a_max, b_max) = (self.max(), other.max());
+		let min = [
+			self.origin.x.max(other.origin.x),
+			self.origin.y.max(other.origin.y),
+		];
+		let max = [a_max.x.min(b_max.x), a_max.y.min(b_max.y)];
+		if max[0] <= min[0] || max[1] <= min[1] {
+			return None;
+		}
+		Geometry::from_min_max(min.into(), max.into())
+	}
+	/// Whether the two rectangles overlap, touching edges don't count.
+	pub fn intersects(&self, other: &Geometry) -> bool {
+		self.intersection(other).is_some()
+	}
+}
 
 lazy_static! {
 	pub static ref ITEM_TYPE_INFO_PANEL: TypeInfo = TypeInfo {
@@ -49,8 +85,10 @@ pub trait Backend: Send + Sync + 'static {
 	fn start_data(&self) -> Result<PanelItemInitData>;
 
 	fn apply_cursor_material(&self, model_part: &Arc<ModelPart>);
+	fn get_cursor_hotspot(&self) -> Option<Vector2<i32>> {
+		None
+	}
 	fn apply_surface_material(&self, surface: SurfaceId, model_part: &Arc<ModelPart>);
-
 	fn close_toplevel(&self);
 	fn auto_size_toplevel(&self);
 	fn set_toplevel_size(&self, size: Vector2<u32>);
@@ -461,3 +499,63 @@ impl InterfaceAspect for ItemInterface {
 		)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::Geometry;
+
+	fn geometry(x: i32, y: i32, w: u32, h: u32) -> Geometry {
+		Geometry {
+			origin: [x, y].into(),
+			size: [w, h].into(),
+		}
+	}
+	fn assert_geometry(geometry: Geometry, x: i32, y: i32, w: u32, h: u32) {
+		assert_eq!((geometry.origin.x, geometry.origin.y), (x, y));
+		assert_eq!((geometry.size.x, geometry.size.y), (w, h));
+	}
+
+	#[test]
+	fn from_min_max_zero_size() {
+		let geometry = Geometry::from_min_max([3, 4].into(), [3, 4].into()).unwrap();
+		assert_geometry(geometry, 3, 4, 0, 0);
+		let geometry = Geometry::from_min_max([3, 4].into(), [3, 10].into()).unwrap();
+		assert_geometry(geometry, 3, 4, 0, 6);
+	}
+	#[test]
+	fn from_min_max_negative_size() {
+		assert!(Geometry::from_min_max([3, 4].into(), [2, 10].into()).is_none());
+		assert!(Geometry::from_min_max([3, 4].into(), [10, 3].into()).is_none());
+		assert!(Geometry::from_min_max([0, 0].into(), [-1, -1].into()).is_none());
+	}
+
+	#[test]
+	fn intersection_zero_size() {
+		assert!(geometry(0, 0, 10, 10)
+			.intersection(&geometry(5, 5, 0, 0))
+			.is_none());
+	}
+	#[test]
+	fn intersection_touching() {
+		assert!(geometry(0, 0, 5, 5)
+			.intersection(&geometry(5, 0, 5, 5))
+			.is_none());
+		assert!(geometry(0, 0, 5, 5)
+			.intersection(&geometry(5, 5, 5, 5))
+			.is_none());
+	}
+	#[test]
+	fn intersection_contained() {
+		let intersection = geometry(0, 0, 10, 10)
+			.intersection(&geometry(2, 3, 4, 5))
+			.unwrap();
+		assert_geometry(intersection, 2, 3, 4, 5);
+	}
+	#[test]
+	fn intersection_partial() {
+		let intersection = geometry(0, 0, 10, 10)
+			.intersection(&geometry(-5, 5, 10, 10))
+			.unwrap();
+		assert_geometry(intersection, 0, 5, 5, 5);
+	}
+}
@@ -56,7 +56,7 @@ pub fn find_closest_capture(
 		.get_valid_contents()
 		.into_iter()
 		.filter_map(|h| {
-			distance_calculator(&method.spatial, &method.data.lock(), &h.field)
+			distance_calculator(method.spatial_ref(), &method.data.lock(), &h.field)
 				.map(|dist| (h.clone(), dist))
 		})
 		.min_by(|(_, dist_a), (_, dist_b)| dist_a.partial_cmp(dist_b).unwrap())
@@ -79,7 +79,7 @@ pub fn get_sorted_handlers(
 				.map_or(false, |node| node.enabled())
 		})
 		.filter_map(|handler| {
-			distance_calculator(&method.spatial, &method.data.lock(), &handler.field)
+			distance_calculator(method.spatial_ref(), &method.data.lock(), &handler.field)
 				.map(|distance| (vec![handler], distance))
 		})
 		.filter(|(_, distance)| *distance > 0.0)
@@ -1,22 +1,133 @@
-use smithay::reexports::wayland_server::{
-	protocol::{
-		wl_data_device::{
-			Request::{Release, SetSelection, StartDrag},
-			WlDataDevice,
-		},
-		wl_data_device_manager::{
-			Request::{CreateDataSource, GetDataDevice},
-			WlDataDeviceManager,
-		},
-		wl_data_source::{
-			Request::{Destroy, Offer, SetActions},
-			WlDataSource,
+use super::state::WaylandState;
+use mint::Vector2;
+use parking_lot::Mutex;
+use smithay::{
+	reexports::wayland_server::{
+		backend::ClientId,
+		protocol::{
+			wl_data_device::{
+				Request::{Release, SetSelection, StartDrag},
+				WlDataDevice,
+			},
+			wl_data_device_manager::{
+				DndAction,
+				Request::{CreateDataSource, GetDataDevice},
+				WlDataDeviceManager,
+			},
+			wl_data_offer::{self, WlDataOffer},
+			wl_data_source::{
+				Request::{Destroy, Offer, SetActions},
+				WlDataSource,
+			},
+			wl_surface::WlSurface,
 		},
+		Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource,
 	},
-	Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource,
+	utils::SERIAL_COUNTER,
 };
+use std::os::fd::AsFd;
 
-use super::state::WaylandState;
+/// Data devices and the drag that's currently being carried between apps.
+#[derive(Default)]
+pub struct DataDeviceState {
+	devices: Vec<WlDataDevice>,
+	/// Source from the last `wl_data_device.start_drag`, offered to whatever the drag enters.
+	drag_source: Option<WlDataSource>,
+	drag_target: Option<WlDataDevice>,
+}
+
+impl WaylandState {
+	/// Carry the current drag along with the pointer into other apps' surfaces.
+	/// `entered` is whether the pointer just moved onto `surface`, `time` is the motion's timestamp.
+	pub fn drag_pointer_motion(
+		&mut self,
+		surface: &WlSurface,
+		entered: bool,
+		position: Vector2<f32>,
+		time: u32,
+	) {
+		let Some(source) = &self.data_device_state.drag_source else {
+			return;
+		};
+		// The source app gets its own pointer events, so its drag is its own business there
+		if source.client() == surface.client() {
+			self.drag_leave();
+		} else if entered || self.data_device_state.drag_target.is_none() {
+			self.drag_enter(surface, position);
+		} else {
+			self.drag_motion(position, time);
+		}
+	}
+	/// Make the current drag enter a surface, offering it the drag source's mime types.
+	fn drag_enter(&mut self, surface: &WlSurface, position: Vector2<f32>) {
+		self.drag_leave();
+		let Some(client) = surface.client() else {
+			return;
+		};
+		let Some(device) = self
+			.data_device_state
+			.devices
+			.iter()
+			.find(|device| device.client().as_ref() == Some(&client))
+			.cloned()
+		else {
+			return;
+		};
+		let source = self.data_device_state.drag_source.clone();
+		let Ok(offer) = client.create_resource::<WlDataOffer, _, WaylandState>(
+			&self.display_handle,
+			device.version(),
+			source.clone(),
+		) else {
+			return;
+		};
+		device.data_offer(&offer);
+		if let Some(mime_types) = source.as_ref().and_then(|s| s.data::<Mutex<Vec<String>>>()) {
+			for mime_type in mime_types.lock().iter() {
+				offer.offer(mime_type.clone());
+			}
+		}
+		if offer.version() >= 3 {
+			offer.source_actions(DndAction::Copy);
+			offer.action(DndAction::Copy);
+		}
+		device.enter(
+			SERIAL_COUNTER.next_serial().into(),
+			surface,
+			position.x as f64,
+			position.y as f64,
+			Some(&offer),
+		);
+		self.data_device_state.drag_target = Some(device);
+	}
+	fn drag_motion(&mut self, position: Vector2<f32>, time: u32) {
+		if let Some(device) = &self.data_device_state.drag_target {
+			device.motion(time, position.x as f64, position.y as f64);
+		}
+	}
+	fn drag_leave(&mut self) {
+		if let Some(device) = self.data_device_state.drag_target.take() {
+			device.leave();
+		}
+	}
+	/// End the current drag on button release, dropping onto the entered surface if there is one
+	/// so it reads the data through its offer, or cancelling it otherwise.
+	pub fn drag_drop(&mut self) {
+		let Some(source) = self.data_device_state.drag_source.take() else {
+			// The source went away mid-drag, so there's nothing left to drop
+			self.drag_leave();
+			return;
+		};
+		let Some(device) = self.data_device_state.drag_target.take() else {
+			source.cancelled();
+			return;
+		};
+		device.drop();
+		if source.version() >= 3 {
+			source.dnd_drop_performed();
+		}
+	}
+}
 
 impl GlobalDispatch<WlDataDeviceManager, (), WaylandState> for WaylandState {
 	fn bind(
@@ -33,7 +144,7 @@ impl GlobalDispatch<WlDataDeviceManager, (), WaylandState> for WaylandState {
 
 impl Dispatch<WlDataDeviceManager, (), WaylandState> for WaylandState {
 	fn request(
-		_state: &mut WaylandState,
+		state: &mut WaylandState,
 		_client: &Client,
 		_resource: &WlDataDeviceManager,
 		request: <WlDataDeviceManager as Resource>::Request,
@@ -43,38 +154,50 @@ impl Dispatch<WlDataDeviceManager, (), WaylandState> for WaylandState {
 	) {
 		match request {
 			CreateDataSource { id } => {
-				data_init.init(id, ());
+				data_init.init(id, Mutex::new(Vec::<String>::new()));
 			}
 			GetDataDevice { id, seat: _ } => {
-				data_init.init(id, ());
+				let device = data_init.init(id, ());
+				state.data_device_state.devices.push(device);
 			}
 			_ => unreachable!(),
 		}
 	}
 }
 
-impl Dispatch<WlDataSource, (), WaylandState> for WaylandState {
+impl Dispatch<WlDataSource, Mutex<Vec<String>>, WaylandState> for WaylandState {
 	fn request(
 		_state: &mut WaylandState,
 		_client: &Client,
 		_resource: &WlDataSource,
 		request: <WlDataSource as Resource>::Request,
-		_data: &(),
+		mime_types: &Mutex<Vec<String>>,
 		_dhandle: &DisplayHandle,
 		_data_init: &mut DataInit<'_, WaylandState>,
 	) {
 		match request {
-			Offer { mime_type: _ } => {}
+			Offer { mime_type } => mime_types.lock().push(mime_type),
 			Destroy => {}
 			SetActions { dnd_actions: _ } => {}
 			_ => unreachable!(),
 		}
 	}
+
+	fn destroyed(
+		state: &mut WaylandState,
+		_client: ClientId,
+		resource: &WlDataSource,
+		_data: &Mutex<Vec<String>>,
+	) {
+		if state.data_device_state.drag_source.as_ref() == Some(resource) {
+			state.data_device_state.drag_source = None;
+		}
+	}
 }
 
 impl Dispatch<WlDataDevice, (), WaylandState> for WaylandState {
 	fn request(
-		_state: &mut WaylandState,
+		state: &mut WaylandState,
 		_client: &Client,
 		_resource: &WlDataDevice,
 		request: <WlDataDevice as Resource>::Request,
@@ -84,11 +207,13 @@ impl Dispatch<WlDataDevice, (), WaylandState> for WaylandState {
 	) {
 		match request {
 			StartDrag {
-				source: _,
+				source,
 				origin: _,
 				icon: _,
 				serial: _,
-			} => {}
+			} => {
+				state.data_device_state.drag_source = source;
+			}
 			SetSelection {
 				source: _,
 				serial: _,
@@ -97,4 +222,41 @@ impl Dispatch<WlDataDevice, (), WaylandState> for WaylandState {
 			_ => unreachable!(),
 		}
 	}
+
+	fn destroyed(state: &mut WaylandState, _client: ClientId, resource: &WlDataDevice, _data: &()) {
+		state
+			.data_device_state
+			.devices
+			.retain(|device| device != resource);
+		if state.data_device_state.drag_target.as_ref() == Some(resource) {
+			state.data_device_state.drag_target = None;
+		}
+	}
+}
+
+impl Dispatch<WlDataOffer, Option<WlDataSource>, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &WlDataOffer,
+		request: wl_data_offer::Request,
+		source: &Option<WlDataSource>,
+		_dhandle: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		let Some(source) = source.as_ref().filter(|source| source.is_alive()) else {
+			return;
+		};
+		match request {
+			wl_data_offer::Request::Accept { mime_type, .. } => source.target(mime_type),
+			wl_data_offer::Request::Receive { mime_type, fd } => source.send(mime_type, fd.as_fd()),
+			wl_data_offer::Request::Finish => {
+				if source.version() >= 3 {
+					source.dnd_finished();
+				}
+			}
+			wl_data_offer::Request::SetActions { .. } | wl_data_offer::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
 }
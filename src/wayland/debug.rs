@@ -0,0 +1,154 @@
+use super::{
+	state::{ClientState, WaylandState},
+	utils::{ChildInfoExt, ToplevelInfoExt, WlSurfaceExt},
+	xdg_shell::{XdgBackend, XdgToplevelState},
+};
+use crate::nodes::{
+	input::{self, InputHandlerDebugInfo, InputMethodDebugInfo},
+	items::panel::{Backend, ChildInfo, PanelItem, SurfaceId, ToplevelInfo},
+};
+use serde::Serialize;
+use smithay::reexports::{
+	wayland_protocols::xdg::decoration::zv1::server::zxdg_toplevel_decoration_v1::Mode,
+	wayland_server::{protocol::wl_surface::WlSurface, Resource},
+};
+use std::sync::Weak;
+
+#[derive(Serialize)]
+struct ToplevelDebugInfo {
+	pid: Option<i32>,
+	info: Option<ToplevelInfo>,
+	state: Option<ToplevelStateDebugInfo>,
+	fullscreen_output: Option<String>,
+	configure_latency_ns: Option<u64>,
+	cursor_hotspot: Option<[i32; 2]>,
+	focus_stack: Vec<SurfaceId>,
+}
+
+#[derive(Serialize)]
+struct ToplevelStateDebugInfo {
+	size: Option<[u32; 2]>,
+	activated: bool,
+	maximized: bool,
+	fullscreen: bool,
+	server_side_decorations: bool,
+}
+impl From<XdgToplevelState> for ToplevelStateDebugInfo {
+	fn from(state: XdgToplevelState) -> Self {
+		ToplevelStateDebugInfo {
+			size: state.size.map(|size| [size.x, size.y]),
+			activated: state.activated,
+			maximized: state.maximized,
+			fullscreen: state.fullscreen,
+			server_side_decorations: state.decoration_mode == Mode::ServerSide,
+		}
+	}
+}
+
+#[derive(Serialize)]
+struct ClientDebugInfo {
+	pid: Option<i32>,
+	uid: Option<u32>,
+	gid: Option<u32>,
+	surface_count: usize,
+	connected_secs: f64,
+}
+
+#[derive(Serialize)]
+struct SeatDebugInfo {
+	keyboard_focus: Option<SurfaceId>,
+	pointer_focus: Option<SurfaceId>,
+	pointer_grab: Option<SurfaceId>,
+	button_mask: u32,
+	shortcuts_inhibited: bool,
+}
+
+#[derive(Serialize)]
+struct DebugDump {
+	compositor_version: u32,
+	clients: Vec<ClientDebugInfo>,
+	toplevels: Vec<ToplevelDebugInfo>,
+	popups: Vec<ChildInfo>,
+	input_methods: Vec<InputMethodDebugInfo>,
+	input_handlers: Vec<InputHandlerDebugInfo>,
+	seat: SeatDebugInfo,
+}
+
+impl WaylandState {
+	/// Pretty-printed JSON snapshot of all clients, live surfaces, input methods/handlers and seat state.
+	pub fn debug_dump(&self) -> String {
+		let clients = self
+			.clients()
+			.into_iter()
+			.map(|client| ClientDebugInfo {
+				pid: client.pid,
+				uid: client.uid,
+				gid: client.gid,
+				surface_count: client.surface_count,
+				connected_secs: client.connected_since.elapsed().as_secs_f64(),
+			})
+			.collect();
+		let toplevels = self
+			.xdg_shell
+			.toplevel_surfaces()
+			.iter()
+			.map(|toplevel| {
+				let wl_surface = toplevel.wl_surface();
+				let panel_item = wl_surface
+					.get_data::<Weak<PanelItem<XdgBackend>>>()
+					.and_then(|panel_item| panel_item.upgrade());
+				ToplevelDebugInfo {
+					pid: wl_surface
+						.client()
+						.and_then(|c| c.get_data::<ClientState>().and_then(|s| s.pid)),
+					info: wl_surface.get_toplevel_info(),
+					state: panel_item
+						.as_ref()
+						.and_then(|panel_item| panel_item.backend.current_state())
+						.map(Into::into),
+					fullscreen_output: panel_item
+						.as_ref()
+						.and_then(|panel_item| panel_item.backend.fullscreen_output()),
+					configure_latency_ns: panel_item
+						.as_ref()
+						.map(|panel_item| panel_item.backend.configure_latency_ns()),
+					cursor_hotspot: panel_item
+						.as_ref()
+						.and_then(|panel_item| panel_item.backend.get_cursor_hotspot())
+						.map(|hotspot| [hotspot.x, hotspot.y]),
+					focus_stack: panel_item
+						.as_ref()
+						.map(|panel_item| panel_item.backend.focus_stack())
+						.unwrap_or_default(),
+				}
+			})
+			.collect();
+		let popups = self
+			.xdg_shell
+			.popup_surfaces()
+			.iter()
+			.filter_map(|popup| popup.wl_surface().get_child_info())
+			.collect();
+		let (input_methods, input_handlers) = input::debug_info();
+
+		let surface_id = |surface: Option<WlSurface>| surface?.get_data::<SurfaceId>();
+		let seat = SeatDebugInfo {
+			keyboard_focus: surface_id(self.seat.current_keyboard_focus()),
+			pointer_focus: surface_id(self.seat.current_pointer_focus()),
+			pointer_grab: self.seat.current_pointer_grab(),
+			button_mask: self.seat.button_mask(),
+			shortcuts_inhibited: self.seat.shortcuts_inhibited(),
+		};
+
+		serde_json::to_string_pretty(&DebugDump {
+			compositor_version: self.compositor_version(),
+			clients,
+			toplevels,
+			popups,
+			input_methods,
+			input_handlers,
+			seat,
+		})
+		.unwrap_or_else(|e| format!("{{\"error\": \"{e}\"}}"))
+	}
+}
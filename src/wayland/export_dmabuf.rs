@@ -0,0 +1,124 @@
+use super::{state::WaylandState, utils::WlSurfaceExt};
+use smithay::{
+	backend::{allocator::Buffer, renderer::utils::RendererSurfaceStateUserData},
+	reexports::{
+		wayland_protocols_wlr::export_dmabuf::v1::server::{
+			zwlr_export_dmabuf_frame_v1::{self, CancelReason, Flags, ZwlrExportDmabufFrameV1},
+			zwlr_export_dmabuf_manager_v1::{self, ZwlrExportDmabufManagerV1},
+		},
+		wayland_server::{
+			backend::GlobalId, Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New,
+		},
+	},
+	wayland::dmabuf,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct ExportDmabufState {
+	_global: GlobalId,
+}
+impl ExportDmabufState {
+	pub fn new(display_handle: &DisplayHandle) -> Self {
+		ExportDmabufState {
+			_global: display_handle
+				.create_global::<WaylandState, ZwlrExportDmabufManagerV1, _>(1, ()),
+		}
+	}
+}
+
+impl GlobalDispatch<ZwlrExportDmabufManagerV1, (), WaylandState> for WaylandState {
+	fn bind(
+		_state: &mut WaylandState,
+		_handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<ZwlrExportDmabufManagerV1>,
+		_global_data: &(),
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		data_init.init(resource, ());
+	}
+}
+
+impl Dispatch<ZwlrExportDmabufManagerV1, (), WaylandState> for WaylandState {
+	fn request(
+		state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZwlrExportDmabufManagerV1,
+		request: zwlr_export_dmabuf_manager_v1::Request,
+		_data: &(),
+		_dhandle: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwlr_export_dmabuf_manager_v1::Request::CaptureOutput { frame, .. } => {
+				let frame = data_init.init(frame, ());
+				// The XR output has no single image, so export the surface the user is typing into.
+				// Clients that attach DMA-BUFs get them handed right back out, no copy needed.
+				let Some(dmabuf) = state.seat.current_keyboard_focus().and_then(|surface| {
+					surface
+						.get_data_raw::<RendererSurfaceStateUserData, _, _>(|surface_states| {
+							let surface_states = surface_states.lock().unwrap();
+							dmabuf::get_dmabuf(surface_states.buffer()?).ok().cloned()
+						})
+						.flatten()
+				}) else {
+					frame.cancel(CancelReason::Permanent);
+					return;
+				};
+
+				let format = dmabuf.format();
+				let modifier: u64 = format.modifier.into();
+				frame.frame(
+					dmabuf.width(),
+					dmabuf.height(),
+					0,
+					0,
+					dmabuf.y_inverted() as u32,
+					Flags::Transient,
+					format.code as u32,
+					(modifier >> 32) as u32,
+					modifier as u32,
+					dmabuf.num_planes() as u32,
+				);
+				for (index, ((fd, offset), stride)) in dmabuf
+					.handles()
+					.zip(dmabuf.offsets())
+					.zip(dmabuf.strides())
+					.enumerate()
+				{
+					// Subsampled planes are smaller than this, but it always covers the plane
+					let size = stride.saturating_mul(dmabuf.height());
+					frame.object(index as u32, fd, size, offset, stride, index as u32);
+				}
+
+				let time = SystemTime::now()
+					.duration_since(UNIX_EPOCH)
+					.unwrap_or_default();
+				frame.ready(
+					(time.as_secs() >> 32) as u32,
+					time.as_secs() as u32,
+					time.subsec_nanos(),
+				);
+			}
+			zwlr_export_dmabuf_manager_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<ZwlrExportDmabufFrameV1, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZwlrExportDmabufFrameV1,
+		request: zwlr_export_dmabuf_frame_v1::Request,
+		_data: &(),
+		_dhandle: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwlr_export_dmabuf_frame_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
@@ -0,0 +1,258 @@
+use super::{state::WaylandState, utils::WlSurfaceExt, xdg_shell::surface_panel_item};
+use crate::nodes::items::panel::Backend;
+use smithay::{
+	reexports::{
+		wayland_protocols::xdg::shell::server::xdg_toplevel::State,
+		wayland_protocols_wlr::foreign_toplevel::v1::server::{
+			zwlr_foreign_toplevel_handle_v1::{self, ZwlrForeignToplevelHandleV1},
+			zwlr_foreign_toplevel_manager_v1::{self, ZwlrForeignToplevelManagerV1},
+		},
+		wayland_server::{
+			backend::{ClientId, GlobalId},
+			protocol::wl_surface::WlSurface,
+			Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource, Weak,
+		},
+	},
+	wayland::shell::xdg::{ToplevelSurface, XdgShellHandler},
+};
+
+pub struct ForeignToplevelState {
+	_global: GlobalId,
+	managers: Vec<ZwlrForeignToplevelManagerV1>,
+	handles: Vec<ZwlrForeignToplevelHandleV1>,
+}
+impl ForeignToplevelState {
+	pub fn new(display_handle: &DisplayHandle) -> Self {
+		ForeignToplevelState {
+			_global: display_handle
+				.create_global::<WaylandState, ZwlrForeignToplevelManagerV1, _>(3, ()),
+			managers: Vec::new(),
+			handles: Vec::new(),
+		}
+	}
+}
+
+pub struct ForeignToplevelHandleData {
+	surface: Weak<WlSurface>,
+}
+
+impl WaylandState {
+	/// Announce a newly mapped toplevel to every taskbar client.
+	pub fn foreign_toplevel_new(&mut self, dh: &DisplayHandle, toplevel: &ToplevelSurface) {
+		for manager in self.foreign_toplevel_state.managers.clone() {
+			self.announce_foreign_toplevel(dh, &manager, toplevel);
+		}
+	}
+	/// Resend title, app ID and state of a toplevel to every handle for it.
+	pub fn foreign_toplevel_update(&self, toplevel: &ToplevelSurface) {
+		for handle in self.foreign_toplevel_handles(toplevel.wl_surface()) {
+			self.send_foreign_toplevel_info(&handle, toplevel);
+		}
+	}
+	pub fn foreign_toplevel_closed(&self, wl_surface: &WlSurface) {
+		for handle in self.foreign_toplevel_handles(wl_surface) {
+			handle.closed();
+		}
+	}
+
+	fn foreign_toplevel_handles(&self, wl_surface: &WlSurface) -> Vec<ZwlrForeignToplevelHandleV1> {
+		self.foreign_toplevel_state
+			.handles
+			.iter()
+			.filter(|handle| {
+				handle
+					.data::<ForeignToplevelHandleData>()
+					.is_some_and(|data| data.surface == *wl_surface)
+			})
+			.cloned()
+			.collect()
+	}
+	fn announce_foreign_toplevel(
+		&mut self,
+		dh: &DisplayHandle,
+		manager: &ZwlrForeignToplevelManagerV1,
+		toplevel: &ToplevelSurface,
+	) {
+		let Some(client) = manager.client() else {
+			return;
+		};
+		let Ok(handle) = client.create_resource::<ZwlrForeignToplevelHandleV1, _, WaylandState>(
+			dh,
+			manager.version(),
+			ForeignToplevelHandleData {
+				surface: toplevel.wl_surface().downgrade(),
+			},
+		) else {
+			return;
+		};
+		manager.toplevel(&handle);
+		for wl_output in self.output.client_outputs(&client) {
+			handle.output_enter(&wl_output);
+		}
+		self.send_foreign_toplevel_info(&handle, toplevel);
+		self.foreign_toplevel_state.handles.push(handle);
+	}
+	fn send_foreign_toplevel_info(
+		&self,
+		handle: &ZwlrForeignToplevelHandleV1,
+		toplevel: &ToplevelSurface,
+	) {
+		let wl_surface = toplevel.wl_surface();
+		if let Some(title) = wl_surface.get_title() {
+			handle.title(title);
+		}
+		if let Some(app_id) = wl_surface.get_app_id() {
+			handle.app_id(app_id);
+		}
+		let states = toplevel.with_pending_state(|s| {
+			[
+				(
+					State::Maximized,
+					zwlr_foreign_toplevel_handle_v1::State::Maximized,
+				),
+				(
+					State::Activated,
+					zwlr_foreign_toplevel_handle_v1::State::Activated,
+				),
+				(
+					State::Fullscreen,
+					zwlr_foreign_toplevel_handle_v1::State::Fullscreen,
+				),
+			]
+			.into_iter()
+			.filter(|(xdg_state, _)| s.states.contains(*xdg_state))
+			.flat_map(|(_, state)| (state as u32).to_ne_bytes())
+			.collect::<Vec<u8>>()
+		});
+		handle.state(states);
+		handle.done();
+	}
+	fn foreign_toplevel_surface(
+		&self,
+		handle_data: &ForeignToplevelHandleData,
+	) -> Option<ToplevelSurface> {
+		let wl_surface = handle_data.surface.upgrade().ok()?;
+		self.xdg_shell
+			.toplevel_surfaces()
+			.iter()
+			.find(|toplevel| toplevel.wl_surface() == &wl_surface)
+			.cloned()
+	}
+}
+
+impl GlobalDispatch<ZwlrForeignToplevelManagerV1, (), WaylandState> for WaylandState {
+	fn bind(
+		state: &mut WaylandState,
+		handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<ZwlrForeignToplevelManagerV1>,
+		_global_data: &(),
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		let manager = data_init.init(resource, ());
+		for toplevel in state.xdg_shell.toplevel_surfaces().to_vec() {
+			state.announce_foreign_toplevel(handle, &manager, &toplevel);
+		}
+		state.foreign_toplevel_state.managers.push(manager);
+	}
+}
+
+impl Dispatch<ZwlrForeignToplevelManagerV1, (), WaylandState> for WaylandState {
+	fn request(
+		state: &mut WaylandState,
+		_client: &Client,
+		resource: &ZwlrForeignToplevelManagerV1,
+		request: zwlr_foreign_toplevel_manager_v1::Request,
+		_data: &(),
+		_dhandle: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwlr_foreign_toplevel_manager_v1::Request::Stop => {
+				state
+					.foreign_toplevel_state
+					.managers
+					.retain(|manager| manager != resource);
+				resource.finished();
+			}
+			_ => unreachable!(),
+		}
+	}
+
+	fn destroyed(
+		state: &mut WaylandState,
+		_client: ClientId,
+		resource: &ZwlrForeignToplevelManagerV1,
+		_data: &(),
+	) {
+		state
+			.foreign_toplevel_state
+			.managers
+			.retain(|manager| manager != resource);
+	}
+}
+
+impl Dispatch<ZwlrForeignToplevelHandleV1, ForeignToplevelHandleData, WaylandState>
+	for WaylandState
+{
+	fn request(
+		state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZwlrForeignToplevelHandleV1,
+		request: zwlr_foreign_toplevel_handle_v1::Request,
+		data: &ForeignToplevelHandleData,
+		_dhandle: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		let Some(toplevel) = state.foreign_toplevel_surface(data) else {
+			return;
+		};
+		match request {
+			zwlr_foreign_toplevel_handle_v1::Request::Activate { .. } => {
+				let Some(panel_item) = surface_panel_item(toplevel.wl_surface()) else {
+					return;
+				};
+				panel_item.backend.set_toplevel_focused_visuals(true);
+				state.foreign_toplevel_update(&toplevel);
+			}
+			zwlr_foreign_toplevel_handle_v1::Request::SetMaximized => {
+				state.maximize_request(toplevel.clone());
+				state.foreign_toplevel_update(&toplevel);
+			}
+			zwlr_foreign_toplevel_handle_v1::Request::SetFullscreen { output } => {
+				state.fullscreen_request(toplevel.clone(), output);
+				state.foreign_toplevel_update(&toplevel);
+			}
+			// Toplevels are always maximized, so leaving fullscreen goes back to that
+			zwlr_foreign_toplevel_handle_v1::Request::UnsetFullscreen => {
+				state.maximize_request(toplevel.clone());
+				state.foreign_toplevel_update(&toplevel);
+			}
+			zwlr_foreign_toplevel_handle_v1::Request::Close => {
+				let Some(panel_item) = surface_panel_item(toplevel.wl_surface()) else {
+					return;
+				};
+				panel_item.backend.close_toplevel();
+			}
+			// There's no minimizing or unmaximizing in XR and no taskbar icon rectangles
+			zwlr_foreign_toplevel_handle_v1::Request::UnsetMaximized
+			| zwlr_foreign_toplevel_handle_v1::Request::SetMinimized
+			| zwlr_foreign_toplevel_handle_v1::Request::UnsetMinimized
+			| zwlr_foreign_toplevel_handle_v1::Request::SetRectangle { .. }
+			| zwlr_foreign_toplevel_handle_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+
+	fn destroyed(
+		state: &mut WaylandState,
+		_client: ClientId,
+		resource: &ZwlrForeignToplevelHandleV1,
+		_data: &ForeignToplevelHandleData,
+	) {
+		state
+			.foreign_toplevel_state
+			.handles
+			.retain(|handle| handle != resource);
+	}
+}
@@ -0,0 +1,309 @@
+use super::{
+	screencopy::{ScreencopyFrame, ScreencopyRequest},
+	state::WaylandState,
+	surface::CoreSurface,
+};
+use parking_lot::Mutex;
+use smithay::{
+	backend::renderer::utils::CommitCounter,
+	reexports::{
+		wayland_protocols::ext::{
+			image_capture_source::v1::server::{
+				ext_image_capture_source_v1::{self, ExtImageCaptureSourceV1},
+				ext_output_image_capture_source_manager_v1::{
+					self, ExtOutputImageCaptureSourceManagerV1,
+				},
+			},
+			image_copy_capture::v1::server::{
+				ext_image_copy_capture_cursor_session_v1::{
+					self, ExtImageCopyCaptureCursorSessionV1,
+				},
+				ext_image_copy_capture_frame_v1::{
+					self, ExtImageCopyCaptureFrameV1, FailureReason,
+				},
+				ext_image_copy_capture_manager_v1::{self, ExtImageCopyCaptureManagerV1},
+				ext_image_copy_capture_session_v1::{self, ExtImageCopyCaptureSessionV1},
+			},
+		},
+		wayland_server::{
+			backend::GlobalId,
+			protocol::{wl_buffer::WlBuffer, wl_shm, wl_surface::WlSurface},
+			Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource, Weak,
+		},
+	},
+	utils::{Buffer as BufferCoords, Rectangle},
+	wayland::shm,
+};
+use std::sync::Arc;
+
+pub struct ImageCopyCaptureState {
+	_source_global: GlobalId,
+	_capture_global: GlobalId,
+}
+impl ImageCopyCaptureState {
+	pub fn new(display_handle: &DisplayHandle) -> Self {
+		ImageCopyCaptureState {
+			_source_global: display_handle
+				.create_global::<WaylandState, ExtOutputImageCaptureSourceManagerV1, _>(1, ()),
+			_capture_global: display_handle
+				.create_global::<WaylandState, ExtImageCopyCaptureManagerV1, _>(1, ()),
+		}
+	}
+}
+
+/// What a capture session is going to copy, decided when the session is created.
+pub struct CaptureSessionData {
+	surface: Option<Weak<WlSurface>>,
+	region: Rectangle<i32, BufferCoords>,
+	/// Commit the session's last frame was captured at, frames only report what changed since.
+	last_commit: Arc<Mutex<Option<CommitCounter>>>,
+}
+
+pub struct CaptureFrameData {
+	surface: Option<Weak<WlSurface>>,
+	region: Rectangle<i32, BufferCoords>,
+	buffer: Mutex<Option<WlBuffer>>,
+	last_commit: Arc<Mutex<Option<CommitCounter>>>,
+}
+
+impl GlobalDispatch<ExtOutputImageCaptureSourceManagerV1, (), WaylandState> for WaylandState {
+	fn bind(
+		_state: &mut WaylandState,
+		_handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<ExtOutputImageCaptureSourceManagerV1>,
+		_global_data: &(),
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		data_init.init(resource, ());
+	}
+}
+
+impl Dispatch<ExtOutputImageCaptureSourceManagerV1, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ExtOutputImageCaptureSourceManagerV1,
+		request: ext_output_image_capture_source_manager_v1::Request,
+		_data: &(),
+		_dhandle: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			ext_output_image_capture_source_manager_v1::Request::CreateSource {
+				source, ..
+			} => {
+				data_init.init(source, ());
+			}
+			ext_output_image_capture_source_manager_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<ExtImageCaptureSourceV1, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ExtImageCaptureSourceV1,
+		request: ext_image_capture_source_v1::Request,
+		_data: &(),
+		_dhandle: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			ext_image_capture_source_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl GlobalDispatch<ExtImageCopyCaptureManagerV1, (), WaylandState> for WaylandState {
+	fn bind(
+		_state: &mut WaylandState,
+		_handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<ExtImageCopyCaptureManagerV1>,
+		_global_data: &(),
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		data_init.init(resource, ());
+	}
+}
+
+impl Dispatch<ExtImageCopyCaptureManagerV1, (), WaylandState> for WaylandState {
+	fn request(
+		state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ExtImageCopyCaptureManagerV1,
+		request: ext_image_copy_capture_manager_v1::Request,
+		_data: &(),
+		_dhandle: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			ext_image_copy_capture_manager_v1::Request::CreateSession { session, .. } => {
+				// Same as screencopy, the only output source is the surface the user is typing into
+				let surface = state.seat.current_keyboard_focus();
+				let size = surface
+					.as_ref()
+					.and_then(CoreSurface::from_wl_surface)
+					.and_then(|c| c.texture_size());
+				let Some(size) = size else {
+					let session = data_init.init(
+						session,
+						CaptureSessionData {
+							surface: None,
+							region: Rectangle::default(),
+							last_commit: Default::default(),
+						},
+					);
+					session.stopped();
+					return;
+				};
+
+				let session = data_init.init(
+					session,
+					CaptureSessionData {
+						surface: surface.map(|s| s.downgrade()),
+						region: Rectangle::from_loc_and_size(
+							(0, 0),
+							(size.x as i32, size.y as i32),
+						),
+						last_commit: Default::default(),
+					},
+				);
+				session.buffer_size(size.x, size.y);
+				session.shm_format(wl_shm::Format::Abgr8888);
+				session.done();
+			}
+			ext_image_copy_capture_manager_v1::Request::CreatePointerCursorSession {
+				session,
+				..
+			} => {
+				// The cursor is part of the XR scene rather than any one image
+				data_init.init(session, ());
+			}
+			ext_image_copy_capture_manager_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<ExtImageCopyCaptureCursorSessionV1, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ExtImageCopyCaptureCursorSessionV1,
+		request: ext_image_copy_capture_cursor_session_v1::Request,
+		_data: &(),
+		_dhandle: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			ext_image_copy_capture_cursor_session_v1::Request::GetCaptureSession { session } => {
+				let session = data_init.init(
+					session,
+					CaptureSessionData {
+						surface: None,
+						region: Rectangle::default(),
+						last_commit: Default::default(),
+					},
+				);
+				session.stopped();
+			}
+			ext_image_copy_capture_cursor_session_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<ExtImageCopyCaptureSessionV1, CaptureSessionData, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ExtImageCopyCaptureSessionV1,
+		request: ext_image_copy_capture_session_v1::Request,
+		data: &CaptureSessionData,
+		_dhandle: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			ext_image_copy_capture_session_v1::Request::CreateFrame { frame } => {
+				data_init.init(
+					frame,
+					CaptureFrameData {
+						surface: data.surface.clone(),
+						region: data.region,
+						buffer: Mutex::new(None),
+						last_commit: data.last_commit.clone(),
+					},
+				);
+			}
+			ext_image_copy_capture_session_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<ExtImageCopyCaptureFrameV1, CaptureFrameData, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		resource: &ExtImageCopyCaptureFrameV1,
+		request: ext_image_copy_capture_frame_v1::Request,
+		data: &CaptureFrameData,
+		_dhandle: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			ext_image_copy_capture_frame_v1::Request::AttachBuffer { buffer } => {
+				data.buffer.lock().replace(buffer);
+			}
+			// Every frame is a full copy, so the buffer's own damage doesn't matter
+			ext_image_copy_capture_frame_v1::Request::DamageBuffer { .. } => (),
+			ext_image_copy_capture_frame_v1::Request::Capture => {
+				let Some(buffer) = data.buffer.lock().take() else {
+					resource.post_error(
+						ext_image_copy_capture_frame_v1::Error::NoBuffer,
+						"Capture requested without a buffer attached",
+					);
+					return;
+				};
+
+				let matches = shm::with_buffer_contents(&buffer, |_, _, buffer_data| {
+					buffer_data.format == wl_shm::Format::Abgr8888
+						&& buffer_data.width == data.region.size.w
+						&& buffer_data.height == data.region.size.h
+				})
+				.unwrap_or(false);
+				if !matches {
+					resource.failed(FailureReason::BufferConstraints);
+					return;
+				}
+
+				let Some(core_surface) = data
+					.surface
+					.as_ref()
+					.and_then(|s| s.upgrade().ok())
+					.as_ref()
+					.and_then(CoreSurface::from_wl_surface)
+				else {
+					resource.failed(FailureReason::Stopped);
+					return;
+				};
+				core_surface
+					.pending_screencopies
+					.lock()
+					.push(ScreencopyRequest {
+						frame: ScreencopyFrame::Ext(resource.clone()),
+						buffer,
+						region: data.region,
+						damage_since: Some(data.last_commit.clone()),
+					});
+			}
+			ext_image_copy_capture_frame_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
@@ -1,6 +1,12 @@
 mod compositor;
 mod data_device;
+mod debug;
 mod decoration;
+mod export_dmabuf;
+mod foreign_toplevel;
+mod image_copy_capture;
+mod output_power;
+mod screencopy;
 mod seat;
 mod state;
 mod surface;
@@ -30,12 +36,17 @@ use std::{
 	ffi::c_void,
 	os::unix::{net::UnixListener, prelude::FromRawFd},
 	sync::Arc,
+	time::Instant,
 };
 use stereokit_rust::system::{Backend, BackendGraphics};
 use tokio::io::unix::AsyncFdReadyGuard;
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::{
-	io::unix::AsyncFd, net::UnixListener as AsyncUnixListener, sync::mpsc, task::JoinHandle,
+	io::unix::AsyncFd,
+	net::UnixListener as AsyncUnixListener,
+	signal::unix::{signal, SignalKind},
+	sync::mpsc,
+	task::JoinHandle,
 };
 use tracing::{debug_span, info, instrument};
 
@@ -154,6 +165,9 @@ impl Wayland {
 
 		let dh1 = display.handle();
 		let mut dh2 = dh1.clone();
+		let (disconnect_tx, mut disconnect_rx) = mpsc::unbounded_channel();
+		// `kill -USR1` the server to log a snapshot of the compositor state
+		let mut debug_dump_signal = signal(SignalKind::user_defined1())?;
 
 		task::new(|| "wayland loop", async move {
 			let _socket = socket; // Keep the socket alive
@@ -161,12 +175,23 @@ impl Wayland {
 				tokio::select! {
 					acc = listen_async.accept() => { // New client connected
 						let (stream, _) = acc?;
+						let cred = stream.peer_cred().ok();
+						let (seat, surface_creation_rate) = {
+							let state = state.lock();
+							(state.seat.clone(), state.surface_creation_rate.clone())
+						};
 						let client_state = Arc::new(ClientState {
-							pid: stream.peer_cred().ok().and_then(|c| c.pid()),
+							pid: cred.and_then(|c| c.pid()),
+							uid: cred.map(|c| c.uid()),
+							gid: cred.map(|c| c.gid()),
+							connected_since: Instant::now(),
+							surface_creation_rate: Mutex::new(surface_creation_rate),
 							id: OnceCell::new(),
 							compositor_state: Default::default(),
-							seat: state.lock().seat.clone(),
+							seat,
+							disconnect_tx: disconnect_tx.clone(),
 						});
+						state.lock().clients.add_raw(&client_state);
 						let _client = dh2.insert_client(stream.into_std()?, client_state.clone())?;
 					}
 					e = dispatch_poll_listener.readable() => { // Dispatch
@@ -178,6 +203,12 @@ impl Wayland {
 						})?;
 						guard.clear_ready();
 					}
+					Some((client_id, pid)) = disconnect_rx.recv() => {
+						state.lock().client_disconnected(client_id, pid);
+					}
+					Some(()) = debug_dump_signal.recv() => {
+						info!("Wayland state: {}", state.lock().debug_dump());
+					}
 				}
 			}
 		})
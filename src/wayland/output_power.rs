@@ -0,0 +1,137 @@
+use super::state::WaylandState;
+use parking_lot::Mutex;
+use smithay::reexports::{
+	wayland_protocols_wlr::output_power_management::v1::server::{
+		zwlr_output_power_manager_v1::{self, ZwlrOutputPowerManagerV1},
+		zwlr_output_power_v1::{self, Mode, ZwlrOutputPowerV1},
+	},
+	wayland_server::{
+		backend::{ClientId, GlobalId},
+		protocol::wl_output::WlOutput,
+		Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource, WEnum,
+	},
+};
+use tracing::info;
+
+pub type OutputPowerMode = Mode;
+
+pub struct OutputPowerState {
+	_global: GlobalId,
+	/// There's only the one output, so its power state lives here.
+	display_power_state: Mutex<OutputPowerMode>,
+	powers: Vec<ZwlrOutputPowerV1>,
+}
+impl OutputPowerState {
+	pub fn new(display_handle: &DisplayHandle) -> Self {
+		OutputPowerState {
+			_global: display_handle
+				.create_global::<WaylandState, ZwlrOutputPowerManagerV1, _>(1, ()),
+			display_power_state: Mutex::new(Mode::On),
+			powers: Vec::new(),
+		}
+	}
+}
+
+impl WaylandState {
+	pub fn display_power(&self) -> OutputPowerMode {
+		*self.output_power_state.display_power_state.lock()
+	}
+	/// Turn the display on or off, e.g. when the headset is taken off.
+	pub fn set_display_power(&mut self, output: &WlOutput, mode: OutputPowerMode) {
+		if !self.output.owns(output) {
+			return;
+		}
+		let old_mode = std::mem::replace(
+			&mut *self.output_power_state.display_power_state.lock(),
+			mode,
+		);
+		if old_mode != mode {
+			self.on_display_power_changed(mode);
+		}
+	}
+	fn on_display_power_changed(&mut self, mode: OutputPowerMode) {
+		info!(?mode, "Display power changed");
+		self.output_power_state
+			.powers
+			.retain(|power| power.is_alive());
+		for power in &self.output_power_state.powers {
+			power.mode(mode);
+		}
+	}
+}
+
+impl GlobalDispatch<ZwlrOutputPowerManagerV1, (), WaylandState> for WaylandState {
+	fn bind(
+		_state: &mut WaylandState,
+		_handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<ZwlrOutputPowerManagerV1>,
+		_global_data: &(),
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		data_init.init(resource, ());
+	}
+}
+
+impl Dispatch<ZwlrOutputPowerManagerV1, (), WaylandState> for WaylandState {
+	fn request(
+		state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZwlrOutputPowerManagerV1,
+		request: zwlr_output_power_manager_v1::Request,
+		_data: &(),
+		_dhandle: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwlr_output_power_manager_v1::Request::GetOutputPower { id, output } => {
+				let owned = state.output.owns(&output);
+				let power = data_init.init(id, output);
+				if !owned {
+					power.failed();
+					return;
+				}
+				power.mode(state.display_power());
+				state.output_power_state.powers.push(power);
+			}
+			zwlr_output_power_manager_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<ZwlrOutputPowerV1, WlOutput, WaylandState> for WaylandState {
+	fn request(
+		state: &mut WaylandState,
+		_client: &Client,
+		resource: &ZwlrOutputPowerV1,
+		request: zwlr_output_power_v1::Request,
+		output: &WlOutput,
+		_dhandle: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwlr_output_power_v1::Request::SetMode { mode } => {
+				let WEnum::Value(mode) = mode else {
+					resource.post_error(zwlr_output_power_v1::Error::InvalidMode, "Invalid mode");
+					return;
+				};
+				state.set_display_power(output, mode);
+			}
+			zwlr_output_power_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+
+	fn destroyed(
+		state: &mut WaylandState,
+		_client: ClientId,
+		resource: &ZwlrOutputPowerV1,
+		_data: &WlOutput,
+	) {
+		state
+			.output_power_state
+			.powers
+			.retain(|power| power != resource);
+	}
+}
@@ -0,0 +1,337 @@
+use super::{state::WaylandState, surface::CoreSurface};
+use parking_lot::Mutex;
+use smithay::{
+	backend::{
+		allocator::Fourcc,
+		renderer::{
+			gles::{GlesRenderer, GlesTexture},
+			utils::CommitCounter,
+			ExportMem, Texture,
+		},
+	},
+	reexports::{
+		wayland_protocols::ext::image_copy_capture::v1::server::ext_image_copy_capture_frame_v1::{
+			ExtImageCopyCaptureFrameV1, FailureReason,
+		},
+		wayland_protocols_wlr::screencopy::v1::server::{
+			zwlr_screencopy_frame_v1::{self, Flags, ZwlrScreencopyFrameV1},
+			zwlr_screencopy_manager_v1::{self, ZwlrScreencopyManagerV1},
+		},
+		wayland_server::{
+			backend::GlobalId,
+			protocol::{wl_buffer::WlBuffer, wl_output::Transform, wl_shm, wl_surface::WlSurface},
+			Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource, Weak,
+		},
+	},
+	utils::{Buffer as BufferCoords, Rectangle},
+	wayland::shm,
+};
+use std::{
+	sync::Arc,
+	time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+pub struct ScreencopyState {
+	_global: GlobalId,
+}
+impl ScreencopyState {
+	pub fn new(display_handle: &DisplayHandle) -> Self {
+		ScreencopyState {
+			_global: display_handle
+				.create_global::<WaylandState, ZwlrScreencopyManagerV1, _>(3, ()),
+		}
+	}
+}
+
+/// What a screencopy frame is going to capture, decided when the frame is created.
+pub struct ScreencopyFrameData {
+	surface: Option<Weak<WlSurface>>,
+	region: Rectangle<i32, BufferCoords>,
+}
+
+/// The protocol object to report a finished (or failed) copy to.
+pub enum ScreencopyFrame {
+	Wlr(ZwlrScreencopyFrameV1),
+	Ext(ExtImageCopyCaptureFrameV1),
+}
+impl ScreencopyFrame {
+	fn failed(&self) {
+		match self {
+			ScreencopyFrame::Wlr(frame) => frame.failed(),
+			ScreencopyFrame::Ext(frame) => frame.failed(FailureReason::Unknown),
+		}
+	}
+	/// `damage` is in surface buffer coordinates, `None` meaning the whole region changed.
+	fn ready(
+		&self,
+		region: Rectangle<i32, BufferCoords>,
+		damage: Option<&[Rectangle<i32, BufferCoords>]>,
+	) {
+		let damage = match damage {
+			Some(damage) => damage
+				.iter()
+				.filter_map(|rect| rect.intersection(region))
+				.map(|rect| Rectangle::from_loc_and_size(rect.loc - region.loc, rect.size))
+				.collect(),
+			None => vec![Rectangle::from_loc_and_size((0, 0), region.size)],
+		};
+		let time = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default();
+		let (tv_sec_hi, tv_sec_lo, tv_nsec) = (
+			(time.as_secs() >> 32) as u32,
+			time.as_secs() as u32,
+			time.subsec_nanos(),
+		);
+		match self {
+			ScreencopyFrame::Wlr(frame) => {
+				frame.flags(Flags::empty());
+				if frame.version() >= 2 {
+					for rect in damage {
+						frame.damage(
+							rect.loc.x as u32,
+							rect.loc.y as u32,
+							rect.size.w as u32,
+							rect.size.h as u32,
+						);
+					}
+				}
+				frame.ready(tv_sec_hi, tv_sec_lo, tv_nsec);
+			}
+			ScreencopyFrame::Ext(frame) => {
+				frame.transform(Transform::Normal);
+				for rect in damage {
+					frame.damage(rect.loc.x, rect.loc.y, rect.size.w, rect.size.h);
+				}
+				frame.presentation_time(tv_sec_hi, tv_sec_lo, tv_nsec);
+				frame.ready();
+			}
+		}
+	}
+}
+
+/// A copy that's waiting on the render thread to read back the surface's texture.
+pub struct ScreencopyRequest {
+	pub(super) frame: ScreencopyFrame,
+	pub(super) buffer: WlBuffer,
+	pub(super) region: Rectangle<i32, BufferCoords>,
+	/// For copies that wait for new damage, the commit the last copy was taken at.
+	pub(super) damage_since: Option<Arc<Mutex<Option<CommitCounter>>>>,
+}
+impl ScreencopyRequest {
+	pub fn fulfill(
+		self,
+		renderer: &mut GlesRenderer,
+		texture: Option<&GlesTexture>,
+		damage: Option<&[Rectangle<i32, BufferCoords>]>,
+	) {
+		let Some(texture) = texture else {
+			self.frame.failed();
+			return;
+		};
+		if self.copy_into_buffer(renderer, texture).is_none() {
+			self.frame.failed();
+			return;
+		}
+		self.frame.ready(self.region, damage);
+	}
+
+	fn copy_into_buffer(&self, renderer: &mut GlesRenderer, texture: &GlesTexture) -> Option<()> {
+		let (region, pixels) = read_texture(renderer, texture, self.region)?;
+
+		shm::with_buffer_contents_mut(&self.buffer, |ptr, len, data| {
+			let row_len = region.size.w as usize * 4;
+			let rows = region.size.h as usize;
+			let (Ok(offset), Ok(stride)) =
+				(usize::try_from(data.offset), usize::try_from(data.stride))
+			else {
+				return None;
+			};
+			let fits = stride >= row_len
+				&& stride
+					.checked_mul(rows)
+					.and_then(|size| size.checked_add(offset))
+					.is_some_and(|end| end <= len)
+				&& pixels.len() >= row_len * rows;
+			if !fits {
+				return None;
+			}
+			for row in 0..rows {
+				let dst_offset = offset + row * stride;
+				let src_offset = row * row_len;
+				// SAFETY: `ptr` is valid for `len` bytes for the duration of this closure, and
+				// `offset + stride * rows <= len` with `row_len <= stride` keeps every row inside it.
+				// The source is a separate Vec holding at least `row_len * rows` bytes.
+				unsafe {
+					std::ptr::copy_nonoverlapping(
+						pixels.as_ptr().add(src_offset),
+						ptr.add(dst_offset),
+						row_len,
+					);
+				}
+			}
+			Some(())
+		})
+		.ok()
+		.flatten()
+	}
+}
+
+/// Copy a region of the texture, clamped to its bounds, returning the clamped region and its pixels.
+fn read_texture(
+	renderer: &mut GlesRenderer,
+	texture: &GlesTexture,
+	region: Rectangle<i32, BufferCoords>,
+) -> Option<(Rectangle<i32, BufferCoords>, Vec<u8>)> {
+	let region = region.intersection(Rectangle::from_loc_and_size(
+		(0, 0),
+		(texture.width() as i32, texture.height() as i32),
+	))?;
+	let mapping = renderer
+		.copy_texture(texture, region, Fourcc::Abgr8888)
+		.ok()?;
+	let pixels = renderer.map_texture(&mapping).ok()?.to_vec();
+	Some((region, pixels))
+}
+
+impl GlobalDispatch<ZwlrScreencopyManagerV1, (), WaylandState> for WaylandState {
+	fn bind(
+		_state: &mut WaylandState,
+		_handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<ZwlrScreencopyManagerV1>,
+		_global_data: &(),
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		data_init.init(resource, ());
+	}
+}
+
+impl Dispatch<ZwlrScreencopyManagerV1, (), WaylandState> for WaylandState {
+	fn request(
+		state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZwlrScreencopyManagerV1,
+		request: zwlr_screencopy_manager_v1::Request,
+		_data: &(),
+		_dhandle: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		let (frame, region) = match request {
+			zwlr_screencopy_manager_v1::Request::CaptureOutput { frame, .. } => (frame, None),
+			zwlr_screencopy_manager_v1::Request::CaptureOutputRegion {
+				frame,
+				x,
+				y,
+				width,
+				height,
+				..
+			} => (
+				frame,
+				Some(Rectangle::from_loc_and_size((x, y), (width, height))),
+			),
+			zwlr_screencopy_manager_v1::Request::Destroy => return,
+			_ => unreachable!(),
+		};
+
+		// The XR output has no single image, so capture the surface the user is typing into
+		let surface = state.seat.current_keyboard_focus();
+		let size = surface
+			.as_ref()
+			.and_then(CoreSurface::from_wl_surface)
+			.and_then(|c| c.texture_size());
+		let Some(size) = size else {
+			let frame = data_init.init(
+				frame,
+				ScreencopyFrameData {
+					surface: None,
+					region: Rectangle::default(),
+				},
+			);
+			frame.failed();
+			return;
+		};
+
+		let full = Rectangle::from_loc_and_size((0, 0), (size.x as i32, size.y as i32));
+		let region = region
+			.map(|r| r.intersection(full).unwrap_or_default())
+			.unwrap_or(full);
+		let frame = data_init.init(
+			frame,
+			ScreencopyFrameData {
+				surface: surface.map(|s| s.downgrade()),
+				region,
+			},
+		);
+		frame.buffer(
+			wl_shm::Format::Abgr8888,
+			region.size.w as u32,
+			region.size.h as u32,
+			region.size.w as u32 * 4,
+		);
+		if frame.version() >= 3 {
+			frame.buffer_done();
+		}
+	}
+}
+
+impl Dispatch<ZwlrScreencopyFrameV1, ScreencopyFrameData, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		resource: &ZwlrScreencopyFrameV1,
+		request: zwlr_screencopy_frame_v1::Request,
+		data: &ScreencopyFrameData,
+		_dhandle: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		let (buffer, with_damage) = match request {
+			zwlr_screencopy_frame_v1::Request::Copy { buffer } => (buffer, false),
+			zwlr_screencopy_frame_v1::Request::CopyWithDamage { buffer } => (buffer, true),
+			zwlr_screencopy_frame_v1::Request::Destroy => return,
+			_ => unreachable!(),
+		};
+
+		let Ok(buffer_data) = shm::with_buffer_contents(&buffer, |_, _, data| data) else {
+			resource.post_error(
+				zwlr_screencopy_frame_v1::Error::InvalidBuffer,
+				"Only wl_shm buffers can be copied into",
+			);
+			return;
+		};
+		if buffer_data.format != wl_shm::Format::Abgr8888
+			|| buffer_data.width != data.region.size.w
+			|| buffer_data.height != data.region.size.h
+		{
+			resource.post_error(
+				zwlr_screencopy_frame_v1::Error::InvalidBuffer,
+				"Buffer doesn't match the advertised parameters",
+			);
+			return;
+		}
+
+		let Some(core_surface) = data
+			.surface
+			.as_ref()
+			.and_then(|s| s.upgrade().ok())
+			.as_ref()
+			.and_then(CoreSurface::from_wl_surface)
+		else {
+			warn!("Screencopy target surface is gone");
+			resource.failed();
+			return;
+		};
+		// Each wlr frame stands alone, so "damage" means anything newer than the copy request
+		let damage_since = with_damage.then(|| Arc::new(Mutex::new(core_surface.current_commit())));
+		core_surface
+			.pending_screencopies
+			.lock()
+			.push(ScreencopyRequest {
+				frame: ScreencopyFrame::Wlr(resource.clone()),
+				buffer,
+				region: data.region,
+				damage_since,
+			});
+	}
+}
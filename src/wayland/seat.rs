@@ -3,28 +3,46 @@ use crate::{
 	core::task,
 	nodes::{
 		data::KEYMAPS,
-		items::panel::{Backend, Geometry, PanelItem},
+		items::panel::{Backend, Geometry, PanelItem, SurfaceId},
 	},
 };
 use mint::Vector2;
+use once_cell::sync::Lazy;
 use parking_lot::Mutex;
+use portable_atomic::{AtomicU32, AtomicUsize, Ordering};
 use rustc_hash::FxHashMap;
 use slotmap::KeyData;
 use smithay::{
-	backend::input::{AxisRelativeDirection, ButtonState, KeyState},
-	delegate_seat,
+	backend::input::{Axis, AxisRelativeDirection, ButtonState, KeyState},
+	delegate_keyboard_shortcuts_inhibit, delegate_pointer_constraints, delegate_seat,
 	input::{
 		keyboard::{FilterResult, LedState},
-		pointer::{AxisFrame, ButtonEvent, CursorImageStatus, MotionEvent},
+		pointer::{
+			AxisFrame, ButtonEvent, CursorImageStatus, CursorImageSurfaceData, MotionEvent,
+			PointerHandle, RelativeMotionEvent,
+		},
 		touch::{self, DownEvent, UpEvent},
 		Seat, SeatHandler,
 	},
 	reexports::wayland_server::{protocol::wl_surface::WlSurface, Resource, Weak as WlWeak},
-	utils::SERIAL_COUNTER,
-	wayland::compositor,
+	utils::{Logical, Point, Serial, SERIAL_COUNTER},
+	wayland::{
+		compositor::{self, RectangleKind, RegionAttributes},
+		keyboard_shortcuts_inhibit::{
+			KeyboardShortcutsInhibitHandler, KeyboardShortcutsInhibitState,
+			KeyboardShortcutsInhibitor,
+		},
+		pointer_constraints::{
+			with_pointer_constraint, PointerConstraint, PointerConstraintsHandler,
+		},
+	},
+};
+use std::{
+	sync::{Arc, Weak},
+	time::Duration,
 };
-use std::sync::{Arc, Weak};
-use tokio::sync::watch;
+use tokio::{sync::watch, task::JoinHandle, time::Instant};
+use tracing::debug;
 
 impl SeatHandler for WaylandState {
 	type PointerFocus = WlSurface;
@@ -34,17 +52,25 @@ impl SeatHandler for WaylandState {
 	fn seat_state(&mut self) -> &mut smithay::input::SeatState<Self> {
 		&mut self.seat_state
 	}
-	fn focus_changed(&mut self, _seat: &Seat<Self>, _focused: Option<&Self::KeyboardFocus>) {}
+	fn focus_changed(&mut self, _seat: &Seat<Self>, _focused: Option<&Self::KeyboardFocus>) {
+		self.seat.stop_all_repeats();
+	}
 	fn cursor_image(&mut self, _seat: &Seat<Self>, image: CursorImageStatus) {
 		self.seat.cursor_info_tx.send_modify(|c| match image {
 			CursorImageStatus::Hidden => c.surface = None,
 			CursorImageStatus::Surface(surface) => {
 				CoreSurface::add_to(&surface);
-				compositor::with_states(&surface, |data| {
+				let hotspot = compositor::with_states(&surface, |data| {
 					if let Some(core_surface) = data.data_map.get::<Arc<CoreSurface>>() {
 						core_surface.set_material_offset(1);
 					}
+					data.data_map
+						.get::<CursorImageSurfaceData>()
+						.map(|attributes| attributes.lock().unwrap().hotspot)
+						.unwrap_or_default()
 				});
+				c.hotspot_x = hotspot.x;
+				c.hotspot_y = hotspot.y;
 				c.surface = Some(surface.downgrade())
 			}
 			_ => (),
@@ -54,6 +80,77 @@ impl SeatHandler for WaylandState {
 }
 delegate_seat!(WaylandState);
 
+impl KeyboardShortcutsInhibitHandler for WaylandState {
+	fn keyboard_shortcuts_inhibit_state(&mut self) -> &mut KeyboardShortcutsInhibitState {
+		&mut self.keyboard_shortcuts_inhibit_state
+	}
+	fn new_inhibitor(&mut self, inhibitor: KeyboardShortcutsInhibitor) {
+		inhibitor.activate();
+		self.seat.active_inhibitors.fetch_add(1, Ordering::Relaxed);
+	}
+	fn inhibitor_destroyed(&mut self, _inhibitor: KeyboardShortcutsInhibitor) {
+		let _ = self.seat.active_inhibitors.fetch_update(
+			Ordering::Relaxed,
+			Ordering::Relaxed,
+			|count| count.checked_sub(1),
+		);
+	}
+}
+delegate_keyboard_shortcuts_inhibit!(WaylandState);
+
+impl PointerConstraintsHandler for WaylandState {
+	fn new_constraint(&mut self, surface: &WlSurface, pointer: &PointerHandle<Self>) {
+		if pointer.current_focus().as_ref() == Some(surface) {
+			with_pointer_constraint(surface, pointer, |constraint| {
+				if let Some(constraint) = constraint {
+					constraint.activate();
+				}
+			});
+		}
+	}
+	// There's no cursor to warp in XR, the next motion event places it anyway
+	fn cursor_position_hint(
+		&mut self,
+		_surface: &WlSurface,
+		_pointer: &PointerHandle<Self>,
+		_location: Point<f64, Logical>,
+	) {
+	}
+}
+delegate_pointer_constraints!(WaylandState);
+
+/// Move a point outside of a confinement region to the closest point inside it.
+fn confine_to_region(
+	region: &RegionAttributes,
+	location: Point<f64, Logical>,
+) -> Point<f64, Logical> {
+	if region.contains(location.to_i32_floor()) {
+		return location;
+	}
+	region
+		.rects
+		.iter()
+		.filter(|(kind, _)| *kind == RectangleKind::Add)
+		// Empty rects would make the clamp bounds cross
+		.filter(|(_, rect)| rect.size.w > 0 && rect.size.h > 0)
+		.map(|(_, rect)| {
+			Point::from((
+				location
+					.x
+					.clamp(rect.loc.x as f64, (rect.loc.x + rect.size.w - 1) as f64),
+				location
+					.y
+					.clamp(rect.loc.y as f64, (rect.loc.y + rect.size.h - 1) as f64),
+			))
+		})
+		.min_by(|a: &Point<f64, Logical>, b| {
+			let distance =
+				|p: &Point<f64, Logical>| (p.x - location.x).powi(2) + (p.y - location.y).powi(2);
+			distance(a).total_cmp(&distance(b))
+		})
+		.unwrap_or(location)
+}
+
 pub fn handle_cursor<B: Backend>(
 	panel_item: &Arc<PanelItem<B>>,
 	mut cursor: watch::Receiver<CursorInfo>,
@@ -77,22 +174,68 @@ pub struct CursorInfo {
 impl CursorInfo {
 	pub fn cursor_data(&self) -> Option<Geometry> {
 		let cursor_size = self.surface.as_ref()?.upgrade().ok()?.get_size()?;
+		// The hotspot is reported on its own, the origin stays at the cursor surface's corner
 		Some(Geometry {
-			origin: [self.hotspot_x, self.hotspot_y].into(),
+			origin: [0, 0].into(),
 			size: cursor_size,
 		})
 	}
 }
 
+/// evdev code of the first mouse button
+const BTN_LEFT: u32 = 0x110;
+
+/// Reference point for input event timestamps, which only have to be monotonic.
+static EVENT_EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+/// Milliseconds since `EVENT_EPOCH`, as input events carry them.
+fn event_time() -> u32 {
+	EVENT_EPOCH.elapsed().as_millis() as u32
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeyboardRepeatInfo {
+	/// Keys per second
+	pub rate: i32,
+	/// Milliseconds before repeating starts
+	pub delay: i32,
+}
+impl Default for KeyboardRepeatInfo {
+	fn default() -> Self {
+		KeyboardRepeatInfo {
+			rate: 25,
+			delay: 500,
+		}
+	}
+}
+
+/// Serial for an enter event on `surface`, the one of the input that focused it if there is one.
+fn enter_serial(surface: &WlSurface) -> Serial {
+	CoreSurface::from_wl_surface(surface)
+		.and_then(|core_surface| core_surface.take_input_serial())
+		.unwrap_or_else(|| SERIAL_COUNTER.next_serial())
+}
+
 pub struct SeatWrapper {
 	wayland_state: Weak<Mutex<WaylandState>>,
 	cursor_info_tx: watch::Sender<CursorInfo>,
 	pub cursor_info_rx: watch::Receiver<CursorInfo>,
 	seat: Seat<WaylandState>,
 	touches: Mutex<FxHashMap<u32, WlWeak<WlSurface>>>,
+	active_touches: Mutex<FxHashMap<u32, Vector2<f32>>>,
+	last_pointer_location: Mutex<Option<Point<f64, Logical>>>,
+	button_state: Mutex<FxHashMap<u32, bool>>,
+	button_mask: AtomicU32,
+	active_inhibitors: AtomicUsize,
+	repeat_info: KeyboardRepeatInfo,
+	compositor_repeat: bool,
+	repeating_keys: Mutex<FxHashMap<u32, JoinHandle<()>>>,
 }
 impl SeatWrapper {
-	pub fn new(wayland_state: Weak<Mutex<WaylandState>>, seat: Seat<WaylandState>) -> Self {
+	pub fn new(
+		wayland_state: Weak<Mutex<WaylandState>>,
+		seat: Seat<WaylandState>,
+		repeat_info: KeyboardRepeatInfo,
+	) -> Self {
 		let (cursor_info_tx, cursor_info_rx) = watch::channel(CursorInfo {
 			surface: None,
 			hotspot_x: 0,
@@ -104,30 +247,132 @@ impl SeatWrapper {
 			cursor_info_rx,
 			seat,
 			touches: Mutex::new(FxHashMap::default()),
+			active_touches: Mutex::new(FxHashMap::default()),
+			last_pointer_location: Mutex::new(None),
+			button_state: Mutex::new(FxHashMap::default()),
+			button_mask: AtomicU32::new(0),
+			active_inhibitors: AtomicUsize::new(0),
+			repeat_info,
+			compositor_repeat: std::env::var("STARDUST_COMPOSITOR_KEY_REPEAT")
+				.is_ok_and(|v| v != "0"),
+			repeating_keys: Mutex::new(FxHashMap::default()),
 		}
 	}
-	pub fn unfocus(&self, surface: &WlSurface, state: &mut WaylandState) {
-		let pointer = self.seat.get_pointer().unwrap();
-		if pointer.current_focus() == Some(surface.clone()) {
-			pointer.motion(
-				state,
-				None,
-				&MotionEvent {
-					location: (0.0, 0.0).into(),
-					serial: SERIAL_COUNTER.next_serial(),
-					time: 0,
-				},
-			)
+
+	pub fn repeat_info(&self) -> KeyboardRepeatInfo {
+		self.repeat_info
+	}
+	/// Whether held keys get repeated by the compositor instead of the client,
+	/// set with `STARDUST_COMPOSITOR_KEY_REPEAT`.
+	pub fn compositor_repeat(&self) -> bool {
+		self.compositor_repeat
+	}
+	/// Keep sending presses of `key` to `surface` at the repeat rate until it's
+	/// released or focus moves, for clients that leave key repeat to the compositor.
+	pub fn repeat_key(self: &Arc<Self>, surface: &WlSurface, key: u32, keymap_id: u64) {
+		let repeat_info = self.repeat_info();
+		if repeat_info.rate <= 0 {
+			return;
+		}
+		let delay = Duration::from_millis(repeat_info.delay.max(0) as u64);
+		let period = Duration::from_secs(1) / repeat_info.rate.clamp(1, 1000) as u32;
+		let seat = Arc::downgrade(self);
+		let surface = surface.downgrade();
+		let Ok(repeat) = task::new(|| "key repeat", async move {
+			let mut interval = tokio::time::interval_at(Instant::now() + delay, period);
+			loop {
+				interval.tick().await;
+				let Some(seat) = seat.upgrade() else {
+					return;
+				};
+				let Ok(surface) = surface.upgrade() else {
+					return;
+				};
+				if seat.current_keyboard_focus().as_ref() != Some(&surface) {
+					return;
+				}
+				seat.keyboard_keys(surface, keymap_id, vec![key as i32]);
+			}
+		}) else {
+			return;
+		};
+		if let Some(old_repeat) = self.repeating_keys.lock().insert(key, repeat) {
+			old_repeat.abort();
+		}
+	}
+	fn stop_repeat(&self, key: u32) {
+		if let Some(repeat) = self.repeating_keys.lock().remove(&key) {
+			repeat.abort();
+		}
+	}
+	fn stop_all_repeats(&self) {
+		for (_, repeat) in self.repeating_keys.lock().drain() {
+			repeat.abort();
+		}
+	}
+	pub fn current_keyboard_focus(&self) -> Option<WlSurface> {
+		self.seat.get_keyboard()?.current_focus()
+	}
+	pub fn current_pointer_focus(&self) -> Option<WlSurface> {
+		self.seat.get_pointer()?.current_focus()
+	}
+	pub fn current_pointer_grab(&self) -> Option<SurfaceId> {
+		let pointer = self.seat.get_pointer()?;
+		if !pointer.is_grabbed() {
+			return None;
 		}
-		let keyboard = self.seat.get_keyboard().unwrap();
-		if keyboard.current_focus() == Some(surface.clone()) {
-			keyboard.set_focus(state, None, SERIAL_COUNTER.next_serial());
+		pointer.current_focus()?.get_data::<SurfaceId>()
+	}
+
+	/// Take every kind of focus away from `surface`. Smithay doesn't send `leave` to surfaces
+	/// that are already dead, so this is also safe once the client is gone.
+	pub fn unfocus(&self, surface: &WlSurface, state: &mut WaylandState) {
+		if let Some(pointer) = self.seat.get_pointer() {
+			if pointer.current_focus() == Some(surface.clone()) {
+				Self::leave_pointer_focus(&pointer, state);
+				self.drop_pointer_focus();
+			}
 		}
-		for (id, touch_surface) in self.touches.lock().iter() {
-			if touch_surface.id() == surface.id() {
-				self.touch_up(*id);
+		if let Some(keyboard) = self.seat.get_keyboard() {
+			if keyboard.current_focus() == Some(surface.clone()) {
+				keyboard.set_focus(state, None, SERIAL_COUNTER.next_serial());
 			}
 		}
+		let touch_ids = self
+			.touches
+			.lock()
+			.iter()
+			.filter(|(_, touch_surface)| touch_surface.id() == surface.id())
+			.map(|(id, _)| *id)
+			.collect::<Vec<_>>();
+		if touch_ids.is_empty() {
+			return;
+		}
+		for id in touch_ids {
+			self.touch_up_with_state(state, id);
+		}
+		self.touch_frame_with_state(state);
+	}
+
+	/// Forget the held buttons and cursor image of the pointer focus without sending any
+	/// protocol events. Smithay's own focus is moved by `leave_pointer_focus`.
+	fn drop_pointer_focus(&self) {
+		self.button_state.lock().clear();
+		self.button_mask.store(0, Ordering::Relaxed);
+		self.cursor_info_tx.send_modify(|c| c.surface = None);
+	}
+	/// Move the pointer focus to nothing, sending `wl_pointer.leave` to the focused surface.
+	fn leave_pointer_focus(pointer: &PointerHandle<WaylandState>, state: &mut WaylandState) {
+		pointer.motion(
+			state,
+			None,
+			&MotionEvent {
+				location: (0.0, 0.0).into(),
+				serial: SERIAL_COUNTER.next_serial(),
+				time: event_time(),
+			},
+		);
+		pointer.frame(state);
 	}
 
 	pub fn pointer_motion(&self, surface: WlSurface, position: Vector2<f32>) {
@@ -138,18 +383,101 @@ impl SeatWrapper {
 		let Some(pointer) = self.seat.get_pointer() else {
 			return;
 		};
+		let mut location: Point<f64, Logical> = (position.x as f64, position.y as f64).into();
+		let last_location = self.last_pointer_location.lock().replace(location);
+		let time = event_time();
+
+		// Constraints only apply while their surface has pointer focus
+		let previous_focus = pointer.current_focus();
+		if previous_focus.as_ref() == Some(&surface) {
+			// Panels only send absolute positions, so pointer-locked apps get the difference
+			if let Some(last_location) = last_location {
+				let delta = location - last_location;
+				pointer.relative_motion(
+					&mut state,
+					Some((surface.clone(), (0.0, 0.0).into())),
+					&RelativeMotionEvent {
+						delta,
+						delta_unaccel: delta,
+						utime: time as u64 * 1000,
+					},
+				);
+			}
+			let constrained = with_pointer_constraint(&surface, &pointer, |constraint| {
+				match constraint.as_deref().filter(|c| c.is_active()) {
+					Some(PointerConstraint::Locked(_)) => None,
+					Some(PointerConstraint::Confined(confined)) => Some(
+						confined
+							.region()
+							.map(|region| confine_to_region(region, location))
+							.unwrap_or(location),
+					),
+					None => Some(location),
+				}
+			});
+			let Some(constrained) = constrained else {
+				pointer.frame(&mut state);
+				return;
+			};
+			location = constrained;
+		} else if let Some(previous_focus) = previous_focus {
+			with_pointer_constraint(&previous_focus, &pointer, |constraint| {
+				if let Some(constraint) = constraint {
+					constraint.deactivate();
+				}
+			});
+		}
+
+		let serial = if previous_focus.as_ref() == Some(&surface) {
+			SERIAL_COUNTER.next_serial()
+		} else {
+			enter_serial(&surface)
+		};
 		pointer.motion(
 			&mut state,
-			Some((surface, (0.0, 0.0).into())),
+			Some((surface.clone(), (0.0, 0.0).into())),
 			&MotionEvent {
-				location: (position.x as f64, position.y as f64).into(),
-				serial: SERIAL_COUNTER.next_serial(),
-				time: 0,
+				location,
+				serial,
+				time,
 			},
 		);
 		pointer.frame(&mut state);
+		state.drag_pointer_motion(
+			&surface,
+			previous_focus.as_ref() != Some(&surface),
+			position,
+			time,
+		);
+
+		with_pointer_constraint(&surface, &pointer, |constraint| {
+			if let Some(constraint) = constraint.filter(|c| !c.is_active()) {
+				constraint.activate();
+			}
+		});
+	}
+	/// Held pointer buttons, bit 0 is `BTN_LEFT` and the rest follow in evdev code order.
+	pub fn button_mask(&self) -> u32 {
+		self.button_mask.load(Ordering::Relaxed)
 	}
 	pub fn pointer_button(&self, button: u32, pressed: bool) {
+		// Wayland requires presses and releases to alternate per button
+		let was_pressed = self
+			.button_state
+			.lock()
+			.insert(button, pressed)
+			.unwrap_or(false);
+		if was_pressed == pressed {
+			debug!(button, pressed, "Dropping duplicate pointer button event");
+			return;
+		}
+		if let Some(bit) = button.checked_sub(BTN_LEFT).filter(|bit| *bit < u32::BITS) {
+			if pressed {
+				self.button_mask.fetch_or(1 << bit, Ordering::Relaxed);
+			} else {
+				self.button_mask.fetch_and(!(1 << bit), Ordering::Relaxed);
+			}
+		}
 		let Some(state) = self.wayland_state.upgrade() else {
 			return;
 		};
@@ -157,6 +485,15 @@ impl SeatWrapper {
 		let Some(pointer) = self.seat.get_pointer() else {
 			return;
 		};
+		let serial = SERIAL_COUNTER.next_serial();
+		// A click is what focuses a surface, so its keyboard enter should carry the click's serial
+		if let Some(focus) = pointer.current_focus().filter(|_| pressed) {
+			if self.current_keyboard_focus().as_ref() != Some(&focus) {
+				if let Some(core_surface) = CoreSurface::from_wl_surface(&focus) {
+					core_surface.set_input_serial(serial);
+				}
+			}
+		}
 		pointer.button(
 			&mut state,
 			&ButtonEvent {
@@ -166,17 +503,46 @@ impl SeatWrapper {
 				} else {
 					ButtonState::Released
 				},
-				serial: SERIAL_COUNTER.next_serial(),
-				time: 0,
+				serial,
+				time: event_time(),
 			},
 		);
 		pointer.frame(&mut state);
+		if !pressed {
+			state.drag_drop();
+		}
 	}
 	pub fn pointer_scroll(
 		&self,
 		scroll_distance: Option<Vector2<f32>>,
 		scroll_steps: Option<Vector2<f32>>,
 	) {
+		// A zero scroll means the scroll sequence ended (e.g. a kinetic scroll came to rest)
+		let is_zero = |v: Option<Vector2<f32>>| v.map_or(true, |v| v.x == 0.0 && v.y == 0.0);
+		if is_zero(scroll_distance) && is_zero(scroll_steps) {
+			self.pointer_axis_stop(Axis::Horizontal);
+			self.pointer_axis_stop(Axis::Vertical);
+			return;
+		}
+		self.send_axis_frame(AxisFrame {
+			source: None,
+			relative_direction: (
+				AxisRelativeDirection::Identical,
+				AxisRelativeDirection::Identical,
+			),
+			time: event_time(),
+			axis: scroll_distance
+				.map(|d| (d.x as f64, d.y as f64))
+				.unwrap_or((0.0, 0.0)),
+			v120: scroll_steps.map(|d| ((d.x * 120.0) as i32, (d.y * 120.0) as i32)),
+			stop: (false, false),
+		});
+	}
+	/// Send `wl_pointer.axis_stop` for `axis` in its own frame.
+	fn pointer_axis_stop(&self, axis: Axis) {
+		self.send_axis_frame(AxisFrame::new(0).stop(axis));
+	}
+	fn send_axis_frame(&self, frame: AxisFrame) {
 		let Some(state) = self.wayland_state.upgrade() else {
 			return;
 		};
@@ -184,25 +550,14 @@ impl SeatWrapper {
 		let Some(pointer) = self.seat.get_pointer() else {
 			return;
 		};
-		pointer.axis(
-			&mut state,
-			AxisFrame {
-				source: None,
-				relative_direction: (
-					AxisRelativeDirection::Identical,
-					AxisRelativeDirection::Identical,
-				),
-				time: 0,
-				axis: scroll_distance
-					.map(|d| (d.x as f64, d.y as f64))
-					.unwrap_or((0.0, 0.0)),
-				v120: scroll_steps.map(|d| ((d.x * 120.0) as i32, (d.y * 120.0) as i32)),
-				stop: (false, false),
-			},
-		);
+		pointer.axis(&mut state, frame);
 		pointer.frame(&mut state);
 	}
 
+	/// Whether a client currently asked for every key combo via `zwp_keyboard_shortcuts_inhibitor_v1`.
+	pub fn shortcuts_inhibited(&self) -> bool {
+		self.active_inhibitors.load(Ordering::Relaxed) > 0
+	}
 	pub fn keyboard_keys(&self, surface: WlSurface, keymap_id: u64, keys: Vec<i32>) {
 		let Some(state) = self.wayland_state.upgrade() else {
 			return;
@@ -215,11 +570,12 @@ impl SeatWrapper {
 			return;
 		};
 
-		keyboard.set_focus(
-			&mut state.lock(),
-			Some(surface),
-			SERIAL_COUNTER.next_serial(),
-		);
+		let serial = if keyboard.current_focus().as_ref() == Some(&surface) {
+			SERIAL_COUNTER.next_serial()
+		} else {
+			enter_serial(&surface)
+		};
+		keyboard.set_focus(&mut state.lock(), Some(surface), serial);
 		if keyboard
 			.set_keymap_from_string(&mut state.lock(), keymap)
 			.is_err()
@@ -227,6 +583,9 @@ impl SeatWrapper {
 			return;
 		}
 		for key in keys {
+			if key < 0 {
+				self.stop_repeat(key.unsigned_abs());
+			}
 			keyboard.input(
 				&mut state.lock(),
 				key.unsigned_abs(),
@@ -236,7 +595,9 @@ impl SeatWrapper {
 					KeyState::Released
 				},
 				SERIAL_COUNTER.next_serial(),
-				0,
+				event_time(),
+				// There are no compositor shortcuts yet, so everything is forwarded either way.
+				// Any added here must be skipped while `shortcuts_inhibited()` is true.
 				|_, _, _| FilterResult::Forward::<()>,
 			);
 		}
@@ -249,17 +610,24 @@ impl SeatWrapper {
 		let Some(touch) = self.seat.get_touch() else {
 			return;
 		};
+		self.touches.lock().insert(id, surface.downgrade());
+		self.active_touches.lock().insert(id, position);
+		let serial = SERIAL_COUNTER.next_serial();
+		if self.current_keyboard_focus().as_ref() != Some(&surface) {
+			if let Some(core_surface) = CoreSurface::from_wl_surface(&surface) {
+				core_surface.set_input_serial(serial);
+			}
+		}
 		touch.down(
 			&mut state.lock(),
 			Some((surface, (0.0, 0.0).into())),
 			&DownEvent {
 				slot: Some(id).into(),
 				location: (position.x as f64, position.y as f64).into(),
-				serial: SERIAL_COUNTER.next_serial(),
-				time: 0,
+				serial,
+				time: event_time(),
 			},
 		);
-		touch.frame(&mut state.lock());
 	}
 	pub fn touch_move(&self, id: u32, position: Vector2<f32>) {
 		let Some(state) = self.wayland_state.upgrade() else {
@@ -271,37 +639,82 @@ impl SeatWrapper {
 		let Some(touch) = self.seat.get_touch() else {
 			return;
 		};
+		self.active_touches.lock().insert(id, position);
 		touch.motion(
 			&mut state.lock(),
 			Some((surface, (0.0, 0.0).into())),
 			&touch::MotionEvent {
 				slot: Some(id).into(),
 				location: (position.x as f64, position.y as f64).into(),
-				time: 0,
+				time: event_time(),
 			},
 		);
-		touch.frame(&mut state.lock());
 	}
 	pub fn touch_up(&self, id: u32) {
 		let Some(state) = self.wayland_state.upgrade() else {
 			return;
 		};
+		self.touch_up_with_state(&mut state.lock(), id);
+	}
+	fn touch_up_with_state(&self, state: &mut WaylandState, id: u32) {
 		let Some(touch) = self.seat.get_touch() else {
 			return;
 		};
+		self.touches.lock().remove(&id);
+		self.active_touches.lock().remove(&id);
 		touch.up(
-			&mut state.lock(),
+			state,
 			&UpEvent {
 				slot: Some(id).into(),
 				serial: SERIAL_COUNTER.next_serial(),
-				time: 0,
+				time: event_time(),
 			},
 		);
-		touch.frame(&mut state.lock());
 	}
+
+	/// End a group of touch events, which clients apply together.
+	pub fn touch_frame(&self) {
+		let Some(state) = self.wayland_state.upgrade() else {
+			return;
+		};
+		self.touch_frame_with_state(&mut state.lock());
+	}
+	fn touch_frame_with_state(&self, state: &mut WaylandState) {
+		let Some(touch) = self.seat.get_touch() else {
+			return;
+		};
+		touch.frame(state);
+	}
+	/// Forget touch points on surfaces that are gone, e.g. because their client disconnected.
+	pub fn forget_dead_touches(&self) {
+		let mut touches = self.touches.lock();
+		touches.retain(|_, surface| surface.upgrade().is_ok());
+		self.active_touches
+			.lock()
+			.retain(|id, _| touches.contains_key(id));
+	}
+	/// Every touch point that's currently down, with where it last was.
+	pub fn get_touch_points(&self) -> Vec<(u32, Vector2<f32>)> {
+		self.active_touches
+			.lock()
+			.iter()
+			.map(|(id, position)| (*id, *position))
+			.collect()
+	}
+	/// Cancel every touch point, so clients throw away the gestures they were in.
 	pub fn reset_input(&self) {
-		for id in self.touches.lock().keys() {
-			self.touch_up(*id)
+		if self.get_touch_points().is_empty() {
+			return;
 		}
+		let Some(state) = self.wayland_state.upgrade() else {
+			return;
+		};
+		let Some(touch) = self.seat.get_touch() else {
+			return;
+		};
+		// `wl_touch.cancel` covers all points at once and needs no frame
+		touch.cancel(&mut state.lock());
+		self.touches.lock().clear();
+		self.active_touches.lock().clear();
 	}
 }
@@ -1,5 +1,19 @@
-use super::seat::SeatWrapper;
-use crate::wayland::drm::wl_drm::WlDrm;
+use super::{
+	data_device::DataDeviceState,
+	export_dmabuf::ExportDmabufState,
+	foreign_toplevel::ForeignToplevelState,
+	image_copy_capture::ImageCopyCaptureState,
+	output_power::OutputPowerState,
+	screencopy::ScreencopyState,
+	seat::{KeyboardRepeatInfo, SeatWrapper},
+	surface::{CoreSurface, CORE_SURFACES},
+	xdg_shell::XdgBackend,
+};
+use crate::{
+	core::registry::Registry,
+	nodes::items::panel::{PanelItem, SurfaceId},
+	wayland::drm::wl_drm::WlDrm,
+};
 use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
 use smithay::{
@@ -8,7 +22,8 @@ use smithay::{
 		egl::EGLDevice,
 		renderer::gles::GlesRenderer,
 	},
-	delegate_dmabuf, delegate_output, delegate_shm,
+	delegate_dmabuf, delegate_output, delegate_relative_pointer, delegate_shm,
+	delegate_single_pixel_buffer,
 	input::{keyboard::XkbConfig, SeatState},
 	output::{Mode, Output, Scale, Subpixel},
 	reexports::{
@@ -18,12 +33,12 @@ use smithay::{
 		},
 		wayland_protocols_misc::server_decoration::server::org_kde_kwin_server_decoration_manager::Mode as DecorationMode,
 		wayland_server::{
-			backend::{ClientData, ClientId, DisconnectReason},
+			backend::{protocol::ProtocolError, ClientData, ClientId, DisconnectReason},
 			protocol::{
-				wl_buffer::WlBuffer, wl_data_device_manager::WlDataDeviceManager,
-				wl_output::WlOutput,
+				wl_buffer::WlBuffer, wl_data_device_manager::WlDataDeviceManager, wl_display,
+				wl_output::WlOutput, wl_surface::WlSurface,
 			},
-			DisplayHandle,
+			DisplayHandle, Resource,
 		},
 	},
 	utils::{Size, Transform},
@@ -33,24 +48,66 @@ use smithay::{
 		dmabuf::{
 			self, DmabufFeedback, DmabufFeedbackBuilder, DmabufGlobal, DmabufHandler, DmabufState,
 		},
+		keyboard_shortcuts_inhibit::KeyboardShortcutsInhibitState,
 		output::OutputHandler,
+		pointer_constraints::PointerConstraintsState,
+		relative_pointer::RelativePointerManagerState,
 		shell::{
 			kde::decoration::KdeDecorationState,
 			xdg::{WmCapabilitySet, XdgShellState},
 		},
 		shm::{ShmHandler, ShmState},
+		single_pixel_buffer::SinglePixelBufferState,
 	},
 };
-use std::sync::Arc;
+use std::{sync::Arc, time::Instant};
 use tokio::sync::mpsc::UnboundedSender;
-use tracing::{info, warn};
+use tracing::{info, trace, warn};
 
 pub struct ClientState {
 	pub pid: Option<i32>,
+	pub uid: Option<u32>,
+	pub gid: Option<u32>,
+	pub connected_since: Instant,
+	pub surface_creation_rate: Mutex<RateLimiter>,
 	pub id: OnceCell<ClientId>,
 	pub compositor_state: CompositorClientState,
 	pub seat: Arc<SeatWrapper>,
+	/// `disconnected` runs mid-dispatch with the state locked, so it's handled from the loop.
+	/// The PID goes along since the `ClientState` is usually gone by then.
+	pub disconnect_tx: UnboundedSender<(ClientId, Option<i32>)>,
+}
+/// Token bucket, refilling `per_second` tokens every second up to `burst`.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+	per_second: f64,
+	burst: f64,
+	tokens: f64,
+	last_refill: Instant,
 }
+impl RateLimiter {
+	pub fn new(per_second: f64, burst: u32) -> Self {
+		RateLimiter {
+			per_second,
+			burst: burst as f64,
+			tokens: burst as f64,
+			last_refill: Instant::now(),
+		}
+	}
+	/// Take a token if there's one left.
+	pub fn try_acquire(&mut self) -> bool {
+		let now = Instant::now();
+		let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+		self.tokens = (self.tokens + elapsed * self.per_second).min(self.burst);
+		self.last_refill = now;
+		if self.tokens < 1.0 {
+			return false;
+		}
+		self.tokens -= 1.0;
+		true
+	}
+}
+
 impl ClientData for ClientState {
 	fn initialized(&self, client_id: ClientId) {
 		info!("Wayland client {:?} connected", client_id);
@@ -62,21 +119,38 @@ impl ClientData for ClientState {
 			"Wayland client {:?} disconnected because {:#?}",
 			client_id, reason
 		);
+		let _ = self.disconnect_tx.send((client_id, self.pid));
 	}
 }
 
 pub struct WaylandState {
+	pub display_handle: DisplayHandle,
 	pub compositor_state: CompositorState,
+	compositor_version: u32,
 	// pub xdg_activation_state: XdgActivationState,
 	pub kde_decoration_state: KdeDecorationState,
 	pub shm_state: ShmState,
+	pub single_pixel_buffer_state: SinglePixelBufferState,
 	dmabuf_state: (DmabufState, DmabufGlobal, Option<DmabufFeedback>),
 	pub drm_formats: Vec<Fourcc>,
 	pub dmabuf_tx: UnboundedSender<(Dmabuf, Option<dmabuf::ImportNotifier>)>,
 	pub seat_state: SeatState<Self>,
 	pub seat: Arc<SeatWrapper>,
+	pub clients: Registry<ClientState>,
+	pub relative_pointer_state: RelativePointerManagerState,
+	pub keyboard_shortcuts_inhibit_state: KeyboardShortcutsInhibitState,
+	pub pointer_constraints_state: PointerConstraintsState,
 	pub xdg_shell: XdgShellState,
+	pub screencopy_state: ScreencopyState,
+	pub export_dmabuf_state: ExportDmabufState,
+	pub foreign_toplevel_state: ForeignToplevelState,
+	pub image_copy_capture_state: ImageCopyCaptureState,
+	pub data_device_state: DataDeviceState,
+	pub output_power_state: OutputPowerState,
 	pub output: Output,
+	/// Limit on how fast each client may create toplevels and popups, copied into new clients.
+	pub surface_creation_rate: RateLimiter,
+	disconnect_callbacks: Vec<Box<dyn Fn(&ClientId, Option<i32>) + Send>>,
 }
 
 impl WaylandState {
@@ -85,11 +159,12 @@ impl WaylandState {
 		renderer: &GlesRenderer,
 		dmabuf_tx: UnboundedSender<(Dmabuf, Option<dmabuf::ImportNotifier>)>,
 	) -> Arc<Mutex<Self>> {
-		let compositor_state = CompositorState::new::<Self>(&display_handle);
+		let compositor_state = CompositorState::new_v6::<Self>(&display_handle);
 		// let xdg_activation_state = XdgActivationState::new::<Self, _>(&display_handle);
 		let kde_decoration_state =
 			KdeDecorationState::new::<Self>(&display_handle, DecorationMode::Server);
 		let shm_state = ShmState::new::<Self>(&display_handle, vec![]);
+		let single_pixel_buffer_state = SinglePixelBufferState::new::<Self>(&display_handle);
 		let render_node = EGLDevice::device_for_display(renderer.egl_context().display())
 			.and_then(|device| device.try_get_render_node());
 		let dmabuf_formats = renderer
@@ -132,8 +207,14 @@ impl WaylandState {
 		let mut seat_state = SeatState::new();
 		let mut seat = seat_state.new_wl_seat(&display_handle, "seat0");
 		seat.add_pointer();
-		seat.add_keyboard(XkbConfig::default(), 200, 25).unwrap();
+		let repeat_info = KeyboardRepeatInfo::default();
+		seat.add_keyboard(XkbConfig::default(), repeat_info.delay, repeat_info.rate)
+			.unwrap();
 		seat.add_touch();
+		let relative_pointer_state = RelativePointerManagerState::new::<Self>(&display_handle);
+		let keyboard_shortcuts_inhibit_state =
+			KeyboardShortcutsInhibitState::new::<Self>(&display_handle);
+		let pointer_constraints_state = PointerConstraintsState::new::<Self>(&display_handle);
 
 		let output = Output::new(
 			"1x".to_owned(),
@@ -149,12 +230,7 @@ impl WaylandState {
 			size: (1024, 1024).into(),
 			refresh: 60000,
 		};
-		output.change_current_state(
-			Some(mode),
-			Some(Transform::Normal),
-			Some(Scale::Integer(2)),
-			None,
-		);
+		output.change_current_state(Some(mode), None, None, None);
 		output.set_preferred(mode);
 
 		let mut xdg_shell = XdgShellState::new::<Self>(&display_handle);
@@ -167,25 +243,166 @@ impl WaylandState {
 		display_handle.create_global::<Self, WlDataDeviceManager, _>(3, ());
 		display_handle.create_global::<Self, ZxdgDecorationManagerV1, _>(1, ());
 		display_handle.create_global::<Self, WlDrm, _>(2, ());
+		let screencopy_state = ScreencopyState::new(&display_handle);
+		let export_dmabuf_state = ExportDmabufState::new(&display_handle);
+		let foreign_toplevel_state = ForeignToplevelState::new(&display_handle);
+		let image_copy_capture_state = ImageCopyCaptureState::new(&display_handle);
+		let output_power_state = OutputPowerState::new(&display_handle);
 
 		info!("Init Wayland compositor");
 
 		Arc::new_cyclic(|weak| {
-			Mutex::new(WaylandState {
+			let seat_wrapper = Arc::new(SeatWrapper::new(weak.clone(), seat, repeat_info));
+			let mut state = WaylandState {
+				display_handle: display_handle.clone(),
 				compositor_state,
+				compositor_version: 6,
 				// xdg_activation_state,
 				kde_decoration_state,
 				shm_state,
+				single_pixel_buffer_state,
 				drm_formats,
 				dmabuf_state,
 				dmabuf_tx,
 				seat_state,
-				seat: Arc::new(SeatWrapper::new(weak.clone(), seat)),
+				seat: seat_wrapper.clone(),
+				clients: Registry::new(),
+				relative_pointer_state,
+				keyboard_shortcuts_inhibit_state,
+				pointer_constraints_state,
 				xdg_shell,
+				screencopy_state,
+				export_dmabuf_state,
+				foreign_toplevel_state,
+				image_copy_capture_state,
+				data_device_state: DataDeviceState::default(),
+				output_power_state,
 				output,
-			})
+				surface_creation_rate: RateLimiter::new(20.0, 50),
+				disconnect_callbacks: Vec::new(),
+			};
+			// Touch points on the client's surfaces would otherwise linger until the next reset
+			state.on_client_disconnect(move |_, _| seat_wrapper.forget_dead_touches());
+			state.change_output_state(Some(Scale::Integer(2)), Some(Transform::Normal));
+			Mutex::new(state)
 		})
 	}
+
+	/// Whether the client owning this surface may create another toplevel or popup.
+	/// Clients over the limit are disconnected with `wl_display.error(no_memory)`.
+	pub fn check_surface_creation_rate(&self, wl_surface: &WlSurface) -> bool {
+		let Some(client) = wl_surface.client() else {
+			return false;
+		};
+		let Some(client_state) = client.get_data::<ClientState>() else {
+			return true;
+		};
+		if client_state.surface_creation_rate.lock().try_acquire() {
+			return true;
+		}
+		warn!(
+			pid = client_state.pid,
+			"Client is creating surfaces too fast, disconnecting it"
+		);
+		client.kill(
+			&self.display_handle,
+			ProtocolError {
+				code: wl_display::Error::NoMemory as u32,
+				object_id: 1,
+				object_interface: "wl_display".to_string(),
+				message: "surface creation rate limit exceeded".to_string(),
+			},
+		);
+		false
+	}
+
+	/// Run `f` on one of the panel item's surfaces and its `CoreSurface`, if both are still there.
+	/// Input can race with a surface being destroyed, so a missing one is only traced,
+	/// callers where it's a real error should warn on `None`.
+	pub fn with_surface_for_panel<R>(
+		panel: &PanelItem<XdgBackend>,
+		id: &SurfaceId,
+		f: impl FnOnce(&WlSurface, &CoreSurface) -> R,
+	) -> Option<R> {
+		let Some(surface) = panel.backend.wl_surface_from_id(id) else {
+			trace!("No surface with this ID on the panel item");
+			return None;
+		};
+		let Some(core_surface) = CoreSurface::from_wl_surface(&surface) else {
+			trace!(?surface, "Surface has no CoreSurface");
+			return None;
+		};
+		Some(f(&surface, &core_surface))
+	}
+
+	/// Version of the `wl_compositor` global, and so the highest `wl_surface` version clients get.
+	pub fn compositor_version(&self) -> u32 {
+		self.compositor_version
+	}
+
+	/// Change the output's scale and/or transform and tell every surface to render at the new one.
+	pub fn change_output_state(&mut self, scale: Option<Scale>, transform: Option<Transform>) {
+		self.output
+			.change_current_state(None, transform, scale, None);
+		let scale = self.output.current_scale().integer_scale();
+		let transform = self.output.current_transform();
+		for core_surface in CORE_SURFACES.get_valid_contents() {
+			core_surface.send_preferred_buffer_state(scale, transform);
+		}
+	}
+
+	/// Call `callback` with the ID and PID (if known) of every client that disconnects from now on.
+	pub fn on_client_disconnect(
+		&mut self,
+		callback: impl Fn(&ClientId, Option<i32>) + Send + 'static,
+	) {
+		self.disconnect_callbacks.push(Box::new(callback));
+	}
+	/// Forget a disconnected client and let everything that registered a callback clean up after it.
+	pub fn client_disconnected(&mut self, client_id: ClientId, pid: Option<i32>) {
+		if let Some(client) = self
+			.clients
+			.get_valid_contents()
+			.into_iter()
+			.find(|client| client.id.get() == Some(&client_id))
+		{
+			self.clients.remove(&client);
+		}
+		self.clients.prune();
+		for callback in &self.disconnect_callbacks {
+			(callback)(&client_id, pid);
+		}
+	}
+
+	pub fn clients(&self) -> Vec<ClientInfo> {
+		let surface_clients = CORE_SURFACES
+			.get_valid_contents()
+			.into_iter()
+			.filter_map(|core_surface| Some(core_surface.wl_surface()?.client()?.id()))
+			.collect::<Vec<_>>();
+		self.clients
+			.get_valid_contents()
+			.into_iter()
+			.map(|client| ClientInfo {
+				pid: client.pid,
+				uid: client.uid,
+				gid: client.gid,
+				surface_count: surface_clients
+					.iter()
+					.filter(|id| client.id.get() == Some(*id))
+					.count(),
+				connected_since: client.connected_since,
+			})
+			.collect()
+	}
+}
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+	pub pid: Option<i32>,
+	pub uid: Option<u32>,
+	pub gid: Option<u32>,
+	pub surface_count: usize,
+	pub connected_since: Instant,
 }
 impl Drop for WaylandState {
 	fn drop(&mut self) {
@@ -219,4 +436,6 @@ impl OutputHandler for WaylandState {
 }
 delegate_dmabuf!(WaylandState);
 delegate_shm!(WaylandState);
+delegate_single_pixel_buffer!(WaylandState);
 delegate_output!(WaylandState);
+delegate_relative_pointer!(WaylandState);
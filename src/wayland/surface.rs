@@ -1,4 +1,4 @@
-use super::utils::WlSurfaceExt;
+use super::{screencopy::ScreencopyRequest, utils::WlSurfaceExt};
 use crate::{
 	core::{delta::Delta, destroy_queue, registry::Registry},
 	nodes::{
@@ -9,31 +9,55 @@ use crate::{
 		items::camera::TexWrapper,
 	},
 };
+use glam::Vec2;
+use mint::Vector2;
 use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
+use portable_atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use send_wrapper::SendWrapper;
 use smithay::{
-	backend::renderer::{
-		gles::{GlesRenderer, GlesTexture},
-		utils::{import_surface_tree, RendererSurfaceStateUserData},
-		Renderer, Texture,
+	backend::{
+		allocator::Fourcc,
+		renderer::{
+			gles::{GlesRenderer, GlesTexture},
+			utils::{import_surface_tree, CommitCounter, RendererSurfaceStateUserData},
+			Bind, Frame, Offscreen, Renderer, Texture, Unbind,
+		},
 	},
 	desktop::utils::send_frames_surface_tree,
 	output::Output,
-	reexports::wayland_server::{self, protocol::wl_surface::WlSurface, Resource},
+	reexports::wayland_server::{
+		self,
+		protocol::{wl_output::Transform, wl_surface::WlSurface},
+		Resource,
+	},
+	utils::{Buffer as BufferCoords, Rectangle, Serial},
+	wayland::compositor::{self, SurfaceAttributes},
+};
+use std::{
+	ffi::c_void,
+	sync::Arc,
+	time::{Duration, Instant},
 };
-use std::{ffi::c_void, sync::Arc, time::Duration};
 use stereokit_rust::{
 	material::{Material, Transparency},
 	shader::Shader,
 	tex::{Tex, TexAddress, TexFormat, TexSample, TexType},
 	util::Time,
 };
+use tracing::warn;
 
 pub static CORE_SURFACES: Registry<CoreSurface> = Registry::new();
+/// Commits a surface can queue up between frame callbacks before it gets logged.
+const MAX_COMMIT_HISTORY: usize = 4;
+/// How much sooner than a display frame after the last one an upload may still happen,
+/// so frame timing jitter doesn't skip uploads.
+const FRAME_INTERVAL_SLACK: Duration = Duration::from_millis(2);
 
 pub struct CoreSurfaceData {
 	wl_tex: Option<SendWrapper<GlesTexture>>,
+	size: Vector2<u32>,
+	commit: CommitCounter,
 }
 impl Drop for CoreSurfaceData {
 	fn drop(&mut self) {
@@ -43,22 +67,37 @@ impl Drop for CoreSurfaceData {
 
 pub struct CoreSurface {
 	pub weak_surface: wayland_server::Weak<WlSurface>,
+	alive: AtomicBool,
+	last_import: Mutex<Option<Instant>>,
 	mapped_data: Mutex<Option<CoreSurfaceData>>,
 	sk_tex: OnceCell<Mutex<TexWrapper>>,
 	sk_mat: OnceCell<Mutex<MaterialWrapper>>,
 	material_offset: Mutex<Delta<u32>>,
+	transform: Mutex<Delta<Transform>>,
 	pub pending_material_applications: Registry<ModelPart>,
+	pub pending_screencopies: Mutex<Vec<ScreencopyRequest>>,
+	pending_commits: AtomicUsize,
+	last_applied_size: Mutex<Option<Vector2<u32>>>,
+	/// 0 unless an input event is about to move focus here.
+	input_serial: AtomicU32,
 }
 
 impl CoreSurface {
 	pub fn add_to(surface: &WlSurface) {
 		let core_surface = CORE_SURFACES.add(CoreSurface {
 			weak_surface: surface.downgrade(),
+			alive: AtomicBool::new(true),
+			last_import: Mutex::new(None),
 			mapped_data: Mutex::new(None),
 			sk_tex: OnceCell::new(),
 			sk_mat: OnceCell::new(),
 			material_offset: Mutex::new(Delta::new(0)),
+			transform: Mutex::new(Delta::new(Transform::Normal)),
 			pending_material_applications: Registry::new(),
+			pending_screencopies: Mutex::new(Vec::new()),
+			pending_commits: AtomicUsize::new(0),
+			last_applied_size: Mutex::new(None),
+			input_serial: AtomicU32::new(0),
 		});
 		surface.insert_data(core_surface);
 	}
@@ -67,6 +106,44 @@ impl CoreSurface {
 		surf.get_data()
 	}
 
+	pub(super) fn committed(&self) {
+		self.pending_commits.fetch_add(1, Ordering::Relaxed);
+		// Only log once per frame, not for every commit past the limit
+		let history_size = self.history_size();
+		if history_size == MAX_COMMIT_HISTORY + 1 {
+			warn!(
+				history_size,
+				"Client is committing faster than frames are rendered"
+			);
+		}
+	}
+	/// How many commits the client made since it last got frame callbacks.
+	pub fn history_size(&self) -> usize {
+		self.pending_commits.load(Ordering::Relaxed)
+	}
+
+	/// Remember the serial of the input event that moved focus to this surface,
+	/// so the enter events it causes carry the same serial.
+	pub fn set_input_serial(&self, serial: Serial) {
+		self.input_serial.store(serial.into(), Ordering::Relaxed);
+	}
+	/// Take the serial stored by `set_input_serial`, so it only goes to the enter it caused.
+	pub fn take_input_serial(&self) -> Option<Serial> {
+		let serial = self.input_serial.swap(0, Ordering::Relaxed);
+		(serial != 0).then(|| Serial::from(serial))
+	}
+
+	/// Whether the xdg role of this surface is still around, see [`CoreSurface::decycle`].
+	pub fn is_alive(&self) -> bool {
+		self.alive.load(Ordering::Relaxed)
+	}
+	/// Mark the surface as done once its toplevel/popup is destroyed,
+	/// so late material applications from async tasks become no-ops.
+	pub fn decycle(&self) {
+		self.alive.store(false, Ordering::Relaxed);
+		self.pending_material_applications.clear();
+	}
+
 	pub fn process(&self, renderer: &mut GlesRenderer) {
 		let Some(wl_surface) = self.wl_surface() else {
 			return;
@@ -91,13 +168,24 @@ impl CoreSurface {
 			Mutex::new(MaterialWrapper(mat))
 		});
 
-		// Import all surface buffers into textures
-		if import_surface_tree(renderer, &wl_surface).is_err() {
-			return;
+		// Don't re-upload faster than the display can show it, frame callbacks are still sent
+		let min_frame_interval = Duration::try_from_secs_f64(Time::get_step_unscaled())
+			.unwrap_or_default()
+			.saturating_sub(FRAME_INTERVAL_SLACK);
+		let mut last_import = self.last_import.lock();
+		let too_soon = last_import.is_some_and(|t| t.elapsed() < min_frame_interval);
+		if !too_soon {
+			*last_import = Some(Instant::now());
+			// Import all surface buffers into textures
+			if import_surface_tree(renderer, &wl_surface).is_err() {
+				return;
+			}
+			self.update_textures(renderer);
 		}
+		drop(last_import);
 
-		self.update_textures(renderer);
 		self.apply_surface_materials();
+		self.process_screencopies(renderer);
 	}
 
 	pub fn update_textures(&self, renderer: &mut GlesRenderer) {
@@ -116,19 +204,44 @@ impl CoreSurface {
 
 		let mut mapped_data = self.mapped_data.lock();
 
-		let Some(smithay_tex) = wl_surface
+		let Some((smithay_tex, commit)) = wl_surface
 			.get_data_raw::<RendererSurfaceStateUserData, _, _>(|surface_states| {
-				surface_states
-					.lock()
-					.unwrap()
+				let surface_states = surface_states.lock().unwrap();
+				let texture = surface_states
 					.texture::<GlesRenderer>(renderer.id())
-					.cloned()
+					.cloned()?;
+				Some((texture, surface_states.current_commit()))
 			})
 			.flatten()
 		else {
 			return;
 		};
 
+		let transform = compositor::with_states(&wl_surface, |states| {
+			states
+				.cached_state
+				.get::<SurfaceAttributes>()
+				.current()
+				.buffer_transform
+		});
+		// The panel shader can only flip UVs, so rotated buffers get drawn upright first,
+		// once per commit since the upright copy is kept in `mapped_data`
+		let smithay_tex = if is_rotated(transform) {
+			let cached_tex = mapped_data
+				.as_ref()
+				.filter(|data| data.commit == commit)
+				.and_then(|data| data.wl_tex.as_ref())
+				.map(|tex| GlesTexture::clone(tex));
+			let Some(upright_tex) =
+				cached_tex.or_else(|| upright_texture(renderer, &smithay_tex, transform))
+			else {
+				return;
+			};
+			upright_tex
+		} else {
+			smithay_tex
+		};
+
 		let Some(sk_tex) = self.sk_tex.get() else {
 			return;
 		};
@@ -154,7 +267,21 @@ impl CoreSurface {
 			sk_mat.lock().0.queue_offset(*material_offset as i32);
 		}
 
+		let mut current_transform = self.transform.lock();
+		if **current_transform != transform {
+			**current_transform = transform;
+		}
+		if let Some(transform) = current_transform.delta() {
+			let (uv_offset, uv_scale) = buffer_transform_uv(*transform);
+			let material = sk_mat.lock();
+			let mut params = material.0.get_all_param_info();
+			params.set_vec2("uv_offset", uv_offset);
+			params.set_vec2("uv_scale", uv_scale);
+		}
+
 		let new_mapped_data = CoreSurfaceData {
+			size: [smithay_tex.width(), smithay_tex.height()].into(),
+			commit,
 			wl_tex: Some(SendWrapper::new(smithay_tex)),
 		};
 		*mapped_data = Some(new_mapped_data);
@@ -172,6 +299,26 @@ impl CoreSurface {
 			None,
 			|_, _| Some(output.clone()),
 		);
+		self.pending_commits.store(0, Ordering::Relaxed);
+	}
+
+	/// Tell the client which buffer scale and transform to render at. Both events only exist
+	/// since `wl_surface` version 6, so clients that bound an older version are skipped.
+	pub fn send_preferred_buffer_state(&self, scale: i32, transform: smithay::utils::Transform) {
+		let Some(wl_surface) = self.wl_surface() else {
+			return;
+		};
+		if wl_surface.version() < 6 {
+			return;
+		}
+		compositor::with_states(&wl_surface, |data| {
+			compositor::send_surface_state(&wl_surface, data, scale, transform)
+		});
+	}
+
+	/// The buffer transform the client last committed with `wl_surface.set_buffer_transform`.
+	pub fn transform(&self) -> Transform {
+		**self.transform.lock()
 	}
 
 	pub fn set_material_offset(&self, material_offset: u32) {
@@ -179,6 +326,9 @@ impl CoreSurface {
 	}
 
 	pub fn apply_material(&self, model_part: &Arc<ModelPart>) {
+		if !self.is_alive() {
+			return;
+		}
 		self.pending_material_applications.add_raw(model_part)
 	}
 
@@ -192,10 +342,135 @@ impl CoreSurface {
 		}
 	}
 
+	fn process_screencopies(&self, renderer: &mut GlesRenderer) {
+		let requests = std::mem::take(&mut *self.pending_screencopies.lock());
+		if requests.is_empty() {
+			return;
+		}
+		let mapped_data = self.mapped_data.lock();
+		let texture = mapped_data
+			.as_ref()
+			.and_then(|d| d.wl_tex.as_ref())
+			.map(|t| &**t);
+		let applied_commit = mapped_data.as_ref().map(|d| d.commit);
+		// Copies that only want changed frames wait here until there's new damage
+		let mut held = Vec::new();
+		for request in requests {
+			let (Some(damage_since), Some(applied_commit)) =
+				(request.damage_since.clone(), applied_commit)
+			else {
+				request.fulfill(renderer, texture, None);
+				continue;
+			};
+			let last_commit = *damage_since.lock();
+			match self.damage_since_last_apply(applied_commit, last_commit) {
+				Some(damage) if !damage.is_empty() => {
+					*damage_since.lock() = Some(applied_commit);
+					request.fulfill(renderer, texture, Some(&damage));
+				}
+				_ => held.push(request),
+			}
+		}
+		self.pending_screencopies.lock().splice(0..0, held);
+	}
+
+	/// Size in pixels of the last texture imported from this surface.
+	pub fn texture_size(&self) -> Option<Vector2<u32>> {
+		self.mapped_data.lock().as_ref().map(|d| d.size)
+	}
+
+	/// Whether the committed buffer size differs from the last time this was called,
+	/// so size changes get passed on once instead of on every commit.
+	pub fn buffer_dimensions_changed(&self) -> bool {
+		let size = self.wl_surface().and_then(|s| s.get_size());
+		let mut last_applied_size = self.last_applied_size.lock();
+		if *last_applied_size == size {
+			return false;
+		}
+		*last_applied_size = size;
+		true
+	}
+
+	/// The commit the client's surface state is currently at, to track damage from.
+	pub fn current_commit(&self) -> Option<CommitCounter> {
+		self.wl_surface()?
+			.get_data_raw::<RendererSurfaceStateUserData, _, _>(|surface_states| {
+				surface_states.lock().unwrap().current_commit()
+			})
+	}
+
+	/// Buffer damage between `commit` and the last applied texture, or `None` if the
+	/// client has committed since then and the damage would be ahead of the texture.
+	fn damage_since_last_apply(
+		&self,
+		applied_commit: CommitCounter,
+		commit: Option<CommitCounter>,
+	) -> Option<Vec<Rectangle<i32, BufferCoords>>> {
+		self.wl_surface()?
+			.get_data_raw::<RendererSurfaceStateUserData, _, _>(|surface_states| {
+				let surface_states = surface_states.lock().unwrap();
+				(surface_states.current_commit() == applied_commit)
+					.then(|| surface_states.damage_since(commit))
+			})
+			.flatten()
+	}
+
 	pub fn wl_surface(&self) -> Option<WlSurface> {
 		self.weak_surface.upgrade().ok()
 	}
 }
+/// Whether `transform` swaps the buffer's axes, which UV offset and scale can't undo.
+fn is_rotated(transform: Transform) -> bool {
+	matches!(
+		transform,
+		Transform::_90 | Transform::_270 | Transform::Flipped90 | Transform::Flipped270
+	)
+}
+/// UV offset and scale that undo a buffer transform.
+/// Rotated buffers are already drawn upright by `upright_texture`, so they get none.
+fn buffer_transform_uv(transform: Transform) -> (Vec2, Vec2) {
+	match transform {
+		Transform::_180 => (Vec2::NEG_ONE, Vec2::NEG_ONE),
+		Transform::Flipped => (Vec2::new(-1.0, 0.0), Vec2::new(-1.0, 1.0)),
+		Transform::Flipped180 => (Vec2::new(0.0, -1.0), Vec2::new(1.0, -1.0)),
+		_ => (Vec2::ZERO, Vec2::ONE),
+	}
+}
+/// Draw `texture` into a new texture with its buffer transform undone.
+fn upright_texture(
+	renderer: &mut GlesRenderer,
+	texture: &GlesTexture,
+	transform: Transform,
+) -> Option<GlesTexture> {
+	let transform = smithay::utils::Transform::from(transform);
+	let src_size = texture.size();
+	let size = transform.transform_size(src_size);
+	let upright: GlesTexture = renderer.create_buffer(Fourcc::Abgr8888, size).ok()?;
+	renderer.bind(upright.clone()).ok()?;
+	let dst = Rectangle::from_loc_and_size((0, 0), (size.w, size.h));
+	let rendered = renderer
+		.render((size.w, size.h).into(), smithay::utils::Transform::Normal)
+		.and_then(|mut frame| {
+			// Marking it all opaque skips blending, so the new texture needs no clear
+			frame.render_texture_from_to(
+				texture,
+				Rectangle::from_loc_and_size((0.0, 0.0), src_size.to_f64()),
+				dst,
+				&[dst],
+				&[dst],
+				transform,
+				1.0,
+			)?;
+			frame.finish()
+		});
+	let _ = renderer.unbind();
+	if let Err(err) = rendered {
+		warn!(?err, "Couldn't draw rotated buffer upright");
+		return None;
+	}
+	Some(upright)
+}
+
 impl Drop for CoreSurface {
 	fn drop(&mut self) {
 		CORE_SURFACES.remove(self);
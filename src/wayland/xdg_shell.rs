@@ -13,10 +13,12 @@ use crate::nodes::{
 use color_eyre::eyre::{eyre, Result};
 use mint::Vector2;
 use parking_lot::Mutex;
+use portable_atomic::{AtomicI32, AtomicU64, Ordering};
 use rand::Rng;
 use rustc_hash::FxHashMap;
 use smithay::{
 	delegate_xdg_shell,
+	output::Output,
 	reexports::{
 		wayland_protocols::xdg::{
 			decoration::zv1::server::zxdg_toplevel_decoration_v1::Mode,
@@ -31,12 +33,15 @@ use smithay::{
 	wayland::{
 		compositor::add_post_commit_hook,
 		shell::xdg::{
-			PopupSurface, PositionerState, ShellClient, ToplevelSurface, XdgShellHandler,
-			XdgShellState,
+			Configure, PopupSurface, PositionerState, ShellClient, ToplevelSurface,
+			XdgShellHandler, XdgShellState,
 		},
 	},
 };
-use std::sync::{Arc, Weak};
+use std::{
+	sync::{Arc, Weak},
+	time::{SystemTime, UNIX_EPOCH},
+};
 use tracing::warn;
 
 fn get_unconstrained_popup_geometry(positioner: &PositionerState) -> Geometry {
@@ -57,6 +62,61 @@ impl From<Rectangle<i32, Logical>> for Geometry {
 	}
 }
 
+/// Configure acks slower than this get logged.
+pub static CONFIGURE_LATENCY_WARN_NS: AtomicU64 = AtomicU64::new(500_000_000);
+/// Popups past this many per toplevel get dismissed right away.
+const MAX_POPUPS: usize = 32;
+/// Popups nested deeper than this get dismissed right away.
+const POPUP_MAX_DEPTH: usize = 8;
+
+/// Why a new popup gets dismissed instead of shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PopupRejection {
+	NoParent,
+	TooMany,
+	TooDeep,
+}
+/// Popups of a panel item from creation on, since they only become children once mapped.
+struct PopupTracker<P>(Vec<P>);
+impl<P> PopupTracker<P> {
+	fn new() -> Self {
+		PopupTracker(Vec::new())
+	}
+	fn add(&mut self, popup: P) {
+		self.0.push(popup);
+	}
+	/// How many popups are still alive, forgetting the rest.
+	fn live_count(&mut self, alive: impl Fn(&P) -> bool) -> usize {
+		self.0.retain(alive);
+		self.0.len()
+	}
+}
+
+/// `popup_count` and `parent_depth` are for the panel item the popup would join,
+/// not counting the new popup itself.
+fn popup_rejection(
+	has_parent: bool,
+	popup_count: usize,
+	parent_depth: usize,
+) -> Option<PopupRejection> {
+	if !has_parent {
+		Some(PopupRejection::NoParent)
+	} else if popup_count >= MAX_POPUPS {
+		Some(PopupRejection::TooMany)
+	} else if parent_depth >= POPUP_MAX_DEPTH {
+		Some(PopupRejection::TooDeep)
+	} else {
+		None
+	}
+}
+
+fn now_ns() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_nanos() as u64
+}
+
 pub fn surface_panel_item(wl_surface: &WlSurface) -> Option<Arc<PanelItem<XdgBackend>>> {
 	let panel_item = wl_surface
 		.get_data::<Weak<PanelItem<XdgBackend>>>()
@@ -77,6 +137,9 @@ impl XdgShellHandler for WaylandState {
 	fn client_destroyed(&mut self, _client: ShellClient) {}
 
 	fn new_toplevel(&mut self, toplevel: ToplevelSurface) {
+		if !self.check_surface_creation_rate(toplevel.wl_surface()) {
+			return;
+		}
 		toplevel.wl_surface().insert_data(SurfaceId::Toplevel(()));
 		toplevel.with_pending_state(|s| {
 			s.decoration_mode = Some(Mode::ServerSide);
@@ -117,6 +180,12 @@ impl XdgShellHandler for WaylandState {
 			.insert_data(Mutex::new(initial_toplevel_info));
 
 		CoreSurface::add_to(toplevel.wl_surface());
+		if let Some(core_surface) = CoreSurface::from_wl_surface(toplevel.wl_surface()) {
+			core_surface.send_preferred_buffer_state(
+				self.output.current_scale().integer_scale(),
+				self.output.current_transform(),
+			);
+		}
 
 		add_post_commit_hook(
 			toplevel.wl_surface(),
@@ -131,32 +200,33 @@ impl XdgShellHandler for WaylandState {
 					.map(|s| Vector2::from([s.x as f32, s.y as f32]));
 				let logical_rectangle = surf.get_geometry().unwrap_or_default();
 
-				let mut size_changed = false;
 				surf.with_toplevel_info(|info| {
 					info.parent = parent;
-					if new_size != info.size {
-						info.size = new_size;
-						size_changed = true;
-					}
+					info.size = new_size;
 					info.min_size = min_size;
 					info.max_size = max_size;
 					info.logical_rectangle = logical_rectangle;
 				});
 
-				if size_changed {
-					let Some(panel_item) = surface_panel_item(surf) else {
-						return;
-					};
-					if let Some(toplevel_info) = surf.get_toplevel_info() {
-						panel_item.toplevel_size_changed(toplevel_info.size);
-					}
+				let Some(panel_item) = surface_panel_item(surf) else {
+					return;
+				};
+				let resized = WaylandState::with_surface_for_panel(
+					&panel_item,
+					&SurfaceId::Toplevel(()),
+					|_, core_surface| core_surface.buffer_dimensions_changed(),
+				);
+				match resized {
+					Some(true) => panel_item.toplevel_size_changed(new_size),
+					Some(false) => (),
+					None => warn!(surface = ?surf, "Committed toplevel has no CoreSurface"),
 				}
 			},
 		);
 
 		add_post_commit_hook(
 			toplevel.wl_surface(),
-			|state: &mut WaylandState, _dh, surf| {
+			|state: &mut WaylandState, dh, surf| {
 				if surface_panel_item(surf).is_some() {
 					return;
 				}
@@ -167,6 +237,7 @@ impl XdgShellHandler for WaylandState {
 					.toplevel_surfaces()
 					.iter()
 					.find(|s| s.wl_surface() == surf)
+					.cloned()
 				else {
 					return;
 				};
@@ -180,16 +251,21 @@ impl XdgShellHandler for WaylandState {
 				handle_cursor(&panel_item, panel_item.backend.seat.cursor_info_rx.clone());
 				surf.insert_data(Arc::downgrade(&panel_item));
 				surf.insert_data(node);
+				state.foreign_toplevel_new(dh, &toplevel);
 			},
 		);
 	}
 	fn toplevel_destroyed(&mut self, toplevel: ToplevelSurface) {
+		self.foreign_toplevel_closed(toplevel.wl_surface());
+		if let Some(core_surface) = CoreSurface::from_wl_surface(toplevel.wl_surface()) {
+			core_surface.decycle();
+		}
 		let Some(panel_item) = surface_panel_item(toplevel.wl_surface()) else {
 			return;
 		};
 		panel_item.backend.seat.unfocus(toplevel.wl_surface(), self);
 		panel_item.backend.toplevel.lock().take();
-		panel_item.backend.children.lock().clear();
+		panel_item.backend.clear_children();
 	}
 	fn app_id_changed(&mut self, toplevel: ToplevelSurface) {
 		let wl_surface = toplevel.wl_surface();
@@ -204,9 +280,27 @@ impl XdgShellHandler for WaylandState {
 		let Some(panel_item) = surface_panel_item(wl_surface) else {
 			return;
 		};
+		panel_item.backend.app_id.lock().replace(app_id.clone());
+		self.foreign_toplevel_update(&toplevel);
 		panel_item.toplevel_app_id_changed(&app_id)
 	}
 
+	fn parent_changed(&mut self, toplevel: ToplevelSurface) {
+		let wl_surface = toplevel.wl_surface();
+		let parent = wl_surface.get_parent();
+		wl_surface.with_toplevel_info(|info| {
+			info.parent = parent;
+		});
+
+		let Some(panel_item) = surface_panel_item(wl_surface) else {
+			return;
+		};
+		// The signal has no way to say the parent was unset, the toplevel info still reflects it
+		let Some(parent) = parent else {
+			return;
+		};
+		panel_item.toplevel_parent_changed(parent);
+	}
 	fn title_changed(&mut self, toplevel: ToplevelSurface) {
 		let wl_surface = toplevel.wl_surface();
 		let Some(title) = wl_surface.get_title() else {
@@ -220,29 +314,65 @@ impl XdgShellHandler for WaylandState {
 		let Some(panel_item) = surface_panel_item(wl_surface) else {
 			return;
 		};
+		panel_item.backend.title.lock().replace(title.clone());
+		self.foreign_toplevel_update(&toplevel);
 		panel_item.toplevel_title_changed(&title)
 	}
 
 	fn new_popup(&mut self, popup: PopupSurface, positioner: PositionerState) {
+		if !self.check_surface_creation_rate(popup.wl_surface()) {
+			return;
+		}
+		let parent = popup.get_parent_surface();
+		let panel_item = parent.as_ref().and_then(surface_panel_item);
+		let (popup_count, parent_depth) = parent
+			.as_ref()
+			.zip(panel_item.as_ref())
+			.map(|(parent, panel_item)| {
+				(
+					panel_item.backend.pending_popup_count(),
+					panel_item.backend.popup_depth(parent),
+				)
+			})
+			.unwrap_or_default();
+		// Rejected popups never get configured, so the client can't map them
+		if let Some(rejection) = popup_rejection(parent.is_some(), popup_count, parent_depth) {
+			match rejection {
+				PopupRejection::NoParent => {
+					warn!(surface = ?popup.wl_surface(), "Popup has no parent, dismissing it")
+				}
+				PopupRejection::TooMany => warn!(
+					popup_count,
+					"Too many popups at once, dismissing the newest one"
+				),
+				PopupRejection::TooDeep => {
+					warn!("Popups nested too deep, dismissing the newest one")
+				}
+			}
+			popup.send_popup_done();
+			return;
+		}
+
+		if let Some(panel_item) = &panel_item {
+			panel_item.backend.track_popup(&popup);
+		}
 		let id = rand::thread_rng().gen_range(0..u64::MAX);
 		popup.wl_surface().insert_data(SurfaceId::Child(id));
-		let Some(parent) = popup.get_parent_surface() else {
-			return;
-		};
+		popup.wl_surface().insert_data(popup.clone());
 		let _ = popup.send_configure();
 		CoreSurface::add_to(popup.wl_surface());
 
+		let (Some(parent), Some(panel_item)) = (parent, panel_item) else {
+			return;
+		};
 		popup.wl_surface().insert_data(Mutex::new(ChildInfo {
 			id,
 			parent: parent.get_data::<SurfaceId>().unwrap(),
 			geometry: get_unconstrained_popup_geometry(&positioner),
-			z_order: 1,
+			z_order: panel_item.backend.next_z_order(),
 			receives_input: true,
 		}));
 
-		let Some(panel_item) = surface_panel_item(&parent) else {
-			return;
-		};
 		let panel_item_weak = Arc::downgrade(&panel_item);
 		add_post_commit_hook(
 			popup.wl_surface(),
@@ -275,6 +405,9 @@ impl XdgShellHandler for WaylandState {
 		panel_item.backend.reposition_child(popup.wl_surface());
 	}
 	fn popup_destroyed(&mut self, popup: PopupSurface) {
+		if let Some(core_surface) = CoreSurface::from_wl_surface(popup.wl_surface()) {
+			core_surface.decycle();
+		}
 		let Some(panel_item) = surface_panel_item(popup.wl_surface()) else {
 			return;
 		};
@@ -284,6 +417,16 @@ impl XdgShellHandler for WaylandState {
 
 	fn grab(&mut self, _popup: PopupSurface, _seat: WlSeat, _serial: Serial) {}
 
+	fn ack_configure(&mut self, surface: WlSurface, configure: Configure) {
+		let Configure::Toplevel(configure) = configure else {
+			return;
+		};
+		let Some(panel_item) = surface_panel_item(&surface) else {
+			return;
+		};
+		panel_item.backend.configure_acked(configure.serial);
+	}
+
 	fn move_request(&mut self, toplevel: ToplevelSurface, _seat: WlSeat, _serial: Serial) {
 		let Some(panel_item) = surface_panel_item(toplevel.wl_surface()) else {
 			return;
@@ -320,47 +463,89 @@ impl XdgShellHandler for WaylandState {
 			s.states.set(State::Maximized);
 			s.states.unset(State::Fullscreen);
 		});
-		toplevel.send_configure();
-
 		let Some(panel_item) = surface_panel_item(toplevel.wl_surface()) else {
+			toplevel.send_configure();
 			return;
 		};
+		panel_item.backend.send_configure_if_idle();
+		panel_item.backend.fullscreen_output.lock().take();
 		panel_item.toplevel_fullscreen_active(false);
 	}
-	fn fullscreen_request(&mut self, toplevel: ToplevelSurface, _output: Option<WlOutput>) {
+	fn fullscreen_request(&mut self, toplevel: ToplevelSurface, output: Option<WlOutput>) {
 		toplevel.with_pending_state(|s| {
 			s.states.set(State::Fullscreen);
 			s.states.unset(State::Maximized);
 		});
-		toplevel.send_configure();
-
 		let Some(panel_item) = surface_panel_item(toplevel.wl_surface()) else {
+			toplevel.send_configure();
 			return;
 		};
+		panel_item.backend.send_configure_if_idle();
+		*panel_item.backend.fullscreen_output.lock() = output;
 		panel_item.toplevel_fullscreen_active(true);
 	}
 }
 delegate_xdg_shell!(WaylandState);
 
+/// Snapshot of the last state the toplevel acked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct XdgToplevelState {
+	pub size: Option<Vector2<u32>>,
+	pub activated: bool,
+	pub maximized: bool,
+	pub fullscreen: bool,
+	pub decoration_mode: Mode,
+}
+
 pub struct XdgBackend {
 	toplevel: Mutex<Option<ToplevelSurface>>,
 	pub children: Mutex<FxHashMap<u64, WlSurface>>,
+	children_by_surface: Mutex<FxHashMap<WlSurface, u64>>,
+	next_z_order: AtomicI32,
+	fullscreen_output: Mutex<Option<WlOutput>>,
+	pending_configure_serial: Mutex<Option<Serial>>,
+	pending_configure_sent_ns: AtomicU64,
+	last_ack_latency_ns: AtomicU64,
+	focus_stack: Mutex<Vec<SurfaceId>>,
+	popups: Mutex<PopupTracker<PopupSurface>>,
+	app_id: Mutex<Option<String>>,
+	title: Mutex<Option<String>>,
 	seat: Arc<SeatWrapper>,
 }
 impl XdgBackend {
 	pub fn create(toplevel: ToplevelSurface, seat: Arc<SeatWrapper>) -> Self {
+		let app_id = toplevel.wl_surface().get_app_id();
+		let title = toplevel.wl_surface().get_title();
 		XdgBackend {
 			toplevel: Mutex::new(Some(toplevel)),
 			children: Mutex::new(FxHashMap::default()),
+			children_by_surface: Mutex::new(FxHashMap::default()),
+			next_z_order: AtomicI32::new(1),
+			fullscreen_output: Mutex::new(None),
+			pending_configure_serial: Mutex::new(None),
+			pending_configure_sent_ns: AtomicU64::new(0),
+			last_ack_latency_ns: AtomicU64::new(0),
+			focus_stack: Mutex::new(Vec::new()),
+			popups: Mutex::new(PopupTracker::new()),
+			app_id: Mutex::new(app_id),
+			title: Mutex::new(title),
 			seat,
 		}
 	}
-	fn wl_surface_from_id(&self, id: &SurfaceId) -> Option<WlSurface> {
+	pub(super) fn wl_surface_from_id(&self, id: &SurfaceId) -> Option<WlSurface> {
 		match id {
 			SurfaceId::Toplevel(_) => Some(self.toplevel.lock().clone()?.wl_surface().clone()),
 			SurfaceId::Child(id) => self.children.lock().get(id).cloned(),
 		}
 	}
+	/// [`WaylandState::with_surface_for_panel`] on this backend's own panel item.
+	fn with_surface<R>(
+		&self,
+		id: &SurfaceId,
+		f: impl FnOnce(&WlSurface, &CoreSurface) -> R,
+	) -> Option<R> {
+		WaylandState::with_surface_for_panel(&self.panel_item()?, id, f)
+	}
 	fn panel_item(&self) -> Option<Arc<PanelItem<XdgBackend>>> {
 		surface_panel_item(self.toplevel.lock().clone()?.wl_surface())
 	}
@@ -372,53 +557,221 @@ impl XdgBackend {
 		let Some(child_info) = surface.get_child_info() else {
 			return;
 		};
+		// Geometry is relative to the parent, so only siblings can be compared
+		let overlapping = self
+			.children
+			.lock()
+			.values()
+			.filter_map(|sibling| sibling.get_child_info())
+			.filter(|sibling| match (&sibling.parent, &child_info.parent) {
+				(SurfaceId::Toplevel(_), SurfaceId::Toplevel(_)) => true,
+				(SurfaceId::Child(a), SurfaceId::Child(b)) => a == b,
+				_ => false,
+			})
+			.filter(|sibling| sibling.geometry.intersects(&child_info.geometry))
+			.map(|sibling| sibling.id)
+			.collect::<Vec<_>>();
+		if !overlapping.is_empty() {
+			warn!(
+				id = child_info.id,
+				?overlapping,
+				"New popup overlaps its sibling popups"
+			);
+		}
 
 		self.children.lock().insert(child_info.id, surface.clone());
+		self.children_by_surface
+			.lock()
+			.insert(surface.clone(), child_info.id);
+		// Subsurfaces are part of their parent, only popups take focus
+		if surface.get_data::<PopupSurface>().is_some() {
+			self.focus_stack
+				.lock()
+				.push(SurfaceId::Child(child_info.id));
+		}
 		panel_item.create_child(child_info.id, &child_info);
 	}
 	pub fn reposition_child(&self, surface: &WlSurface) {
 		let Some(panel_item) = self.panel_item() else {
 			return;
 		};
+		let Some(id) = self.popup_by_surface(surface) else {
+			return;
+		};
 		let Some(child_info) = surface.get_child_info() else {
 			return;
 		};
 
-		panel_item.reposition_child(child_info.id, &child_info.geometry);
+		panel_item.reposition_child(id, &child_info.geometry);
 	}
 	pub fn drop_child(&self, surface: &WlSurface) {
 		let Some(panel_item) = self.panel_item() else {
 			return;
 		};
-		let Some(child_info) = surface.get_child_info() else {
+		let Some(id) = self.children_by_surface.lock().remove(surface) else {
+			return;
+		};
+		self.focus_stack
+			.lock()
+			.retain(|focus| !matches!(focus, SurfaceId::Child(child) if *child == id));
+		panel_item.destroy_child(id);
+		self.children.lock().remove(&id);
+	}
+	/// Remember the configure the client has to ack, and start timing the round trip
+	/// unless one is already in flight.
+	fn configure_sent(&self, serial: Serial) {
+		*self.pending_configure_serial.lock() = Some(serial);
+		let _ = self.pending_configure_sent_ns.compare_exchange(
+			0,
+			now_ns(),
+			Ordering::Relaxed,
+			Ordering::Relaxed,
+		);
+	}
+	fn configure_acked(&self, serial: Serial) {
+		{
+			let mut pending_serial = self.pending_configure_serial.lock();
+			// Acking an older configure still leaves the latest one pending
+			if pending_serial.is_some_and(|pending| !serial.is_no_older_than(&pending)) {
+				return;
+			}
+			pending_serial.take();
+		}
+		let sent = self.pending_configure_sent_ns.swap(0, Ordering::Relaxed);
+		if sent != 0 {
+			let latency = now_ns().saturating_sub(sent);
+			self.last_ack_latency_ns.store(latency, Ordering::Relaxed);
+			if latency > CONFIGURE_LATENCY_WARN_NS.load(Ordering::Relaxed) {
+				warn!(
+					latency_ms = latency as f64 / 1_000_000.0,
+					"Toplevel was slow to ack a configure"
+				);
+			}
+		}
+		// Send whatever changed while the client was busy with the last configure
+		self.send_configure_if_idle();
+	}
+	/// Whether the toplevel hasn't acked the last configure yet.
+	pub fn configure_pending(&self) -> bool {
+		self.pending_configure_serial.lock().is_some()
+	}
+	/// Send the pending toplevel state if it changed, unless a configure is still unacked.
+	/// In that case it stays pending and goes out once the client acks.
+	pub fn send_configure_if_idle(&self) {
+		if self.configure_pending() {
+			return;
+		}
+		let Some(toplevel) = self.toplevel.lock().clone() else {
 			return;
 		};
-		panel_item.destroy_child(child_info.id);
-		self.children.lock().remove(&child_info.id);
+		if let Some(serial) = toplevel.send_pending_configure() {
+			self.configure_sent(serial);
+		}
+	}
+	/// How long the toplevel took to ack its last configure.
+	pub fn configure_latency_ns(&self) -> u64 {
+		self.last_ack_latency_ns.load(Ordering::Relaxed)
+	}
+	/// Surfaces of the app that had keyboard focus, the most recent last.
+	pub fn focus_stack(&self) -> Vec<SurfaceId> {
+		self.focus_stack.lock().clone()
+	}
+	/// Name of the output the client asked to go fullscreen on, if it picked one.
+	pub fn fullscreen_output(&self) -> Option<String> {
+		let wl_output = self.fullscreen_output.lock().clone()?;
+		Some(Output::from_resource(&wl_output)?.name())
+	}
+	/// Popups stack in creation order, above the toplevel.
+	fn next_z_order(&self) -> i32 {
+		self.next_z_order.fetch_add(1, Ordering::Relaxed)
+	}
+	/// Dismiss every popup, like desktop compositors do when a window loses focus.
+	pub fn dismiss_popups(&self) {
+		let popups = self
+			.children
+			.lock()
+			.values()
+			.filter_map(|surface| Some((surface.clone(), surface.get_data::<PopupSurface>()?)))
+			.collect::<Vec<_>>();
+		for (surface, popup) in popups {
+			popup.send_popup_done();
+			self.drop_child(&surface);
+		}
+	}
+	/// The toplevel's app ID, cached so it doesn't need to go through the surface's state.
+	pub fn get_app_id(&self) -> Option<String> {
+		self.app_id.lock().clone()
+	}
+	/// The toplevel's title, cached so it doesn't need to go through the surface's state.
+	pub fn get_title(&self) -> Option<String> {
+		self.title.lock().clone()
+	}
+	/// Current info of a single popup or subsurface.
+	pub fn child_data(&self, id: u64) -> Option<ChildInfo> {
+		self.children.lock().get(&id)?.get_child_info()
+	}
+	/// How many popups are alive, mapped or not, used to throttle clients spamming them.
+	/// Subsurfaces are part of their parent's content, so they don't count.
+	pub fn pending_popup_count(&self) -> usize {
+		self.popups.lock().live_count(PopupSurface::alive)
+	}
+	fn track_popup(&self, popup: &PopupSurface) {
+		self.popups.lock().add(popup.clone());
+	}
+	/// How many children deep `surface` is, 0 for the toplevel. Stops counting past `POPUP_MAX_DEPTH`.
+	pub fn popup_depth(&self, surface: &WlSurface) -> usize {
+		let mut depth = 0;
+		let mut child_info = surface.get_child_info();
+		while let Some(info) = child_info {
+			depth += 1;
+			if depth > POPUP_MAX_DEPTH {
+				break;
+			}
+			child_info = match info.parent {
+				SurfaceId::Child(id) => self.child_data(id),
+				SurfaceId::Toplevel(_) => None,
+			};
+		}
+		depth
+	}
+	/// Find the ID of the popup (or subsurface) that this surface belongs to.
+	pub fn popup_by_surface(&self, wl_surface: &WlSurface) -> Option<u64> {
+		self.children_by_surface.lock().get(wl_surface).copied()
+	}
+	pub fn current_state(&self) -> Option<XdgToplevelState> {
+		let state = self.toplevel.lock().as_ref()?.current_state();
+		Some(XdgToplevelState {
+			size: state
+				.size
+				.map(|s| [s.w.max(0) as u32, s.h.max(0) as u32].into()),
+			activated: state.states.contains(State::Activated),
+			maximized: state.states.contains(State::Maximized),
+			fullscreen: state.states.contains(State::Fullscreen),
+			decoration_mode: state.decoration_mode.unwrap_or(Mode::ServerSide),
+		})
+	}
+	fn clear_children(&self) {
+		self.children.lock().clear();
+		self.children_by_surface.lock().clear();
 	}
 }
 impl Backend for XdgBackend {
 	fn start_data(&self) -> Result<PanelItemInitData> {
-		let cursor = self
-			.seat
-			.cursor_info_rx
-			.borrow()
-			.surface
-			.clone()
-			.and_then(|s| s.upgrade().ok())
-			.as_ref()
-			.and_then(|c| c.get_size())
-			.map(|size| Geometry {
-				origin: [0; 2].into(),
-				size,
-			});
+		let cursor = self.seat.cursor_info_rx.borrow().cursor_data();
 
-		let toplevel_info = self
+		let mut toplevel_info = self
 			.toplevel
 			.lock()
 			.as_ref()
 			.and_then(|toplevel| toplevel.wl_surface().get_toplevel_info())
 			.ok_or(eyre!("Internal: no toplevel or ToplevelInfo"))?;
+		// Until the first buffer is committed, the acked configure size is the best guess
+		if toplevel_info.size.x == 0 || toplevel_info.size.y == 0 {
+			if let Some(size) = self.current_state().and_then(|state| state.size) {
+				toplevel_info.size = size;
+				toplevel_info.logical_rectangle.size = size;
+			}
+		}
 
 		let children = self
 			.children
@@ -427,11 +780,17 @@ impl Backend for XdgBackend {
 			.filter_map(|v| v.get_child_info())
 			.collect();
 
+		// The seat is shared, so only report the grab if it's on one of our surfaces
+		let pointer_grab = self.seat.current_pointer_grab().filter(|id| {
+			let surface = self.wl_surface_from_id(id);
+			surface.is_some() && surface == self.seat.current_pointer_focus()
+		});
+
 		Ok(PanelItemInitData {
 			cursor,
 			toplevel: toplevel_info,
 			children,
-			pointer_grab: None,
+			pointer_grab,
 			keyboard_grab: None,
 		})
 	}
@@ -452,16 +811,16 @@ impl Backend for XdgBackend {
 		};
 		core_surface.apply_material(model_part);
 	}
+	fn get_cursor_hotspot(&self) -> Option<Vector2<i32>> {
+		let cursor_info = self.seat.cursor_info_rx.borrow();
+		cursor_info.surface.as_ref()?;
+		Some([cursor_info.hotspot_x, cursor_info.hotspot_y].into())
+	}
 	fn apply_surface_material(&self, surface: SurfaceId, model_part: &Arc<ModelPart>) {
-		let Some(surface) = self.wl_surface_from_id(&surface) else {
-			return;
-		};
-		let Some(core_surface) = CoreSurface::from_wl_surface(&surface) else {
-			return;
-		};
-		core_surface.apply_material(model_part);
+		self.with_surface(&surface, |_, core_surface| {
+			core_surface.apply_material(model_part)
+		});
 	}
-
 	fn close_toplevel(&self) {
 		if let Some(toplevel) = self.toplevel.lock().clone() {
 			toplevel.send_close();
@@ -473,7 +832,7 @@ impl Backend for XdgBackend {
 			return;
 		};
 		toplevel.with_pending_state(|s| s.size = None);
-		toplevel.send_configure();
+		self.send_configure_if_idle();
 	}
 	fn set_toplevel_size(&self, size: Vector2<u32>) {
 		let Some(toplevel) = self.toplevel.lock().clone() else {
@@ -482,7 +841,7 @@ impl Backend for XdgBackend {
 		toplevel.with_pending_state(|s| {
 			s.size = Some((size.x.max(16) as i32, size.y.max(16) as i32).into())
 		});
-		toplevel.send_pending_configure();
+		self.send_configure_if_idle();
 	}
 	fn set_toplevel_focused_visuals(&self, focused: bool) {
 		let Some(toplevel) = self.toplevel.lock().clone() else {
@@ -494,14 +853,23 @@ impl Backend for XdgBackend {
 			} else {
 				s.states.unset(State::Activated);
 			}
-		})
+		});
+		self.send_configure_if_idle();
+		if focused {
+			let mut focus_stack = self.focus_stack.lock();
+			if focus_stack.is_empty() {
+				focus_stack.push(SurfaceId::Toplevel(()));
+			}
+		} else {
+			self.dismiss_popups();
+			self.focus_stack.lock().clear();
+		}
 	}
 
 	fn pointer_motion(&self, surface: &SurfaceId, position: Vector2<f32>) {
-		let Some(surface) = self.wl_surface_from_id(surface) else {
-			return;
-		};
-		self.seat.pointer_motion(surface, position)
+		self.with_surface(surface, |surface, _| {
+			self.seat.pointer_motion(surface.clone(), position)
+		});
 	}
 	fn pointer_button(&self, _surface: &SurfaceId, button: u32, pressed: bool) {
 		self.seat.pointer_button(button, pressed)
@@ -516,25 +884,88 @@ impl Backend for XdgBackend {
 	}
 
 	fn keyboard_keys(&self, surface: &SurfaceId, keymap_id: u64, keys: Vec<i32>) {
-		let Some(surface) = self.wl_surface_from_id(surface) else {
-			return;
-		};
-		self.seat.keyboard_keys(surface, keymap_id, keys)
+		self.with_surface(surface, |surface, _| {
+			let pressed = keys
+				.iter()
+				.filter(|key| **key > 0)
+				.map(|key| key.unsigned_abs())
+				.collect::<Vec<_>>();
+			self.seat.keyboard_keys(surface.clone(), keymap_id, keys);
+			if self.seat.compositor_repeat() {
+				for key in pressed {
+					self.seat.repeat_key(surface, key, keymap_id);
+				}
+			}
+		});
 	}
 
 	fn touch_down(&self, surface: &SurfaceId, id: u32, position: Vector2<f32>) {
-		let Some(surface) = self.wl_surface_from_id(surface) else {
-			return;
-		};
-		self.seat.touch_down(surface, id, position)
+		self.with_surface(surface, |surface, _| {
+			self.seat.touch_down(surface.clone(), id, position);
+			self.seat.touch_frame();
+		});
 	}
 	fn touch_move(&self, id: u32, position: Vector2<f32>) {
-		self.seat.touch_move(id, position)
+		self.seat.touch_move(id, position);
+		self.seat.touch_frame();
 	}
 	fn touch_up(&self, id: u32) {
-		self.seat.touch_up(id)
+		self.seat.touch_up(id);
+		self.seat.touch_frame();
 	}
 	fn reset_input(&self) {
 		self.seat.reset_input()
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{popup_rejection, PopupRejection, PopupTracker, MAX_POPUPS, POPUP_MAX_DEPTH};
+
+	#[test]
+	fn parentless_popup_is_rejected() {
+		assert_eq!(popup_rejection(false, 0, 0), Some(PopupRejection::NoParent));
+		// Not having a parent wins over every other reason
+		assert_eq!(
+			popup_rejection(false, MAX_POPUPS + 1, POPUP_MAX_DEPTH),
+			Some(PopupRejection::NoParent)
+		);
+	}
+	#[test]
+	fn parented_popup_is_accepted() {
+		assert_eq!(popup_rejection(true, 0, 0), None);
+		assert_eq!(
+			popup_rejection(true, MAX_POPUPS - 1, POPUP_MAX_DEPTH - 1),
+			None
+		);
+	}
+	#[test]
+	fn popup_past_the_limit_is_rejected() {
+		assert_eq!(
+			popup_rejection(true, MAX_POPUPS, 0),
+			Some(PopupRejection::TooMany)
+		);
+	}
+	#[test]
+	fn unmapped_popups_count_toward_the_limit() {
+		// Popups that were created but never committed are tracked all the same
+		let mut popups = PopupTracker::new();
+		for _ in 0..MAX_POPUPS {
+			popups.add(true);
+		}
+		let popup_count = popups.live_count(|alive| *alive);
+		assert_eq!(
+			popup_rejection(true, popup_count, 0),
+			Some(PopupRejection::TooMany)
+		);
+	}
+	#[test]
+	fn destroyed_popups_stop_counting() {
+		let mut popups = PopupTracker::new();
+		popups.add(true);
+		popups.add(false);
+		popups.add(true);
+		assert_eq!(popups.live_count(|alive| *alive), 2);
+		assert_eq!(popups.0.len(), 2);
+	}
+}